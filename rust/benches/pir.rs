@@ -20,6 +20,7 @@ use raidpir::server::RaidPirServer;
 use raidpir::types::RaidPirData;
 
 use hybridpir::client::HybridPirClient;
+use hybridpir::params::HybridPirParams;
 use hybridpir::server::HybridPirServer;
 use hybridpir::types::*;
 
@@ -37,13 +38,17 @@ fn bench_query(c: &mut Criterion) {
         let raidpir_size = 1usize << exp;
 
         group.bench_with_input(BenchmarkId::new("HybridPir", raidpir_size), &raidpir_size, |bench, raidpir_size| {
-            let client = HybridPirClient::new(size, 8,
-                raidpir_servers, raidpir_redundancy, *raidpir_size,
-                2048, 12, 2);
+            let params = HybridPirParams::builder(size, 8)
+                .raidpir_servers(raidpir_servers)
+                .raidpir_redundancy(raidpir_redundancy)
+                .raidpir_size(*raidpir_size)
+                .build().unwrap();
+
+            let client = HybridPirClient::new(&params).unwrap();
 
             let seeds = vec![1234, 4321];
 
-            bench.iter(|| client.query(index, &seeds));
+            bench.iter(|| client.query(index, &seeds).unwrap());
         });
     }
 
@@ -162,22 +167,23 @@ fn bench_pem_100k(c: &mut Criterion) {
             }
 
             group.bench_with_input(BenchmarkId::new(format!("HybridPir,n=2^{}", elements_exp), sealpir_size_exp), &raidpir_size, |bench, raidpir_size| {
+                let params = HybridPirParams::builder(db.len(), element_bytes)
+                    .raidpir_servers(raidpir_servers)
+                    .raidpir_redundancy(raidpir_redundancy)
+                    .raidpir_size(*raidpir_size)
+                    .build().unwrap();
+
                 let mut servers: Vec<Arc<HybridPirServer>> = (0..raidpir_servers)
-                    .map(|i| Arc::new(HybridPirServer::new(
-                        &db,
-                        i, raidpir_servers, raidpir_redundancy, *raidpir_size, false,
-                        2048, 12, 2
-                    ))).collect();
+                    .map(|i| Arc::new(HybridPirServer::new(&db, i, &params).unwrap()))
+                    .collect();
 
-                let client = HybridPirClient::new(db.len(), element_bytes,
-                    raidpir_servers, raidpir_redundancy, *raidpir_size,
-                    2048, 12, 2);
+                let client = HybridPirClient::new(&params).unwrap();
 
                 let servers_setup = servers.clone();
                 bench.iter_batched(|| {servers_setup.iter().for_each(|s| s.preprocess())}, |()| {
                     let seeds = servers.iter_mut().map(|s| s.seed()).collect();
 
-                    let (raidpir_queries, sealpir_query) = client.query(index, &seeds);
+                    let (raidpir_queries, sealpir_query) = client.query(index, &seeds).unwrap();
 
                     if let Some((_, speed)) = connection {
                         let len = &raidpir_queries[0].clone().into_vec().len();
@@ -198,7 +204,7 @@ fn bench_pem_100k(c: &mut Criterion) {
                         std::thread::sleep(Duration::from_secs_f32(responses[0].reply.len() as f32 * 8.0 / (speed * 1_000_000.0)));
                     }
 
-                    client.combine(index, responses);
+                    client.combine(index, responses).unwrap();
                 }, BatchSize::NumIterations(32));
             });
         }
@@ -320,22 +326,23 @@ fn bench_online(c: &mut Criterion) {
             let raidpir_size = 1usize << exp;
 
             group.bench_with_input(BenchmarkId::new(format!("HybridPir,n=2^{}", size_exp), sealpir_size_exp), &raidpir_size, |bench, raidpir_size| {
+                let params = HybridPirParams::builder(db.len(), element_size)
+                    .raidpir_servers(raidpir_servers)
+                    .raidpir_redundancy(raidpir_redundancy)
+                    .raidpir_size(*raidpir_size)
+                    .build().unwrap();
+
                 let mut servers: Vec<HybridPirServer> = (0..raidpir_servers)
-                    .map(|i| HybridPirServer::new(
-                        &db,
-                        i, raidpir_servers, raidpir_redundancy, *raidpir_size, false,
-                        2048, 12, 2
-                    )).collect();
+                    .map(|i| HybridPirServer::new(&db, i, &params).unwrap())
+                    .collect();
 
-                let client = HybridPirClient::new(db.len(), element_size,
-                    raidpir_servers, raidpir_redundancy, *raidpir_size,
-                    2048, 12, 2);
+                let client = HybridPirClient::new(&params).unwrap();
 
                 let servers_setup = servers.clone();
                 bench.iter_batched(|| {servers_setup.iter().for_each(|s| s.preprocess())}, |()| {
                     let seeds = servers.iter_mut().map(|s| s.seed()).collect();
 
-                    let (raidpir_queries, sealpir_query) = client.query(index, &seeds);
+                    let (raidpir_queries, sealpir_query) = client.query(index, &seeds).unwrap();
 
                     if let Some((_, speed)) = connection {
                         let len = raidpir_queries[0].clone().into_vec().len();
@@ -357,7 +364,7 @@ fn bench_online(c: &mut Criterion) {
                     }
 
                     // TODO: see above
-                    client.combine(index, responses);
+                    client.combine(index, responses).unwrap();
                 }, BatchSize::NumIterations(32));
             });
         }
@@ -387,20 +394,22 @@ fn bench_serialization(c: &mut Criterion) {
         for exp in [8, 10, 12, 14, 16].iter() {
             let raidpir_size = 1usize << exp;
 
+            let params = HybridPirParams::builder(db.len(), 8)
+                .raidpir_servers(raidpir_servers)
+                .raidpir_redundancy(raidpir_redundancy)
+                .raidpir_size(raidpir_size)
+                .raidpir_russians(true)
+                .build().unwrap();
+
             let mut servers: Vec<HybridPirServer> = (0..raidpir_servers)
-                .map(|i| HybridPirServer::new(
-                    &db,
-                    i, raidpir_servers, raidpir_redundancy, raidpir_size, true,
-                    2048, 12, 2
-                )).collect();
+                .map(|i| HybridPirServer::new(&db, i, &params).unwrap())
+                .collect();
 
-            let client = HybridPirClient::new(db.len(), 8,
-                raidpir_servers, raidpir_redundancy, raidpir_size,
-                2048, 12, 2);
+            let client = HybridPirClient::new(&params).unwrap();
 
             let seeds = servers.iter_mut().map(|s| s.seed()).collect();
 
-            let (raidpir_queries, sealpir_query) = client.query(index, &seeds);
+            let (raidpir_queries, sealpir_query) = client.query(index, &seeds).unwrap();
 
             let sealpir_key = client.sealpir_key();
 
@@ -408,7 +417,6 @@ fn bench_serialization(c: &mut Criterion) {
                 bench.iter(|| {
                     let msg = HybridPirMessage::Query(
                         raidpir_queries[0].clone().into_vec(),
-                        sealpir_key.clone(),
                         sealpir_query.clone());
                     let _serialized = bincode::serialize(&msg).unwrap();
                 });
@@ -416,7 +424,6 @@ fn bench_serialization(c: &mut Criterion) {
 
             let msg = HybridPirMessage::Query(
                 raidpir_queries[0].clone().into_vec(),
-                sealpir_key.clone(),
                 sealpir_query.clone());
             let serialized = bincode::serialize(&msg).unwrap();
 
@@ -448,7 +455,7 @@ fn bench_serialization(c: &mut Criterion) {
                 });
             });
 
-            client.combine(index, responses);
+            client.combine(index, responses).unwrap();
         }
     }
 }
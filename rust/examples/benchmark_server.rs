@@ -1,5 +1,6 @@
 use std::io::Error;
 use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
 use std::time::Duration;
 
 use rand::rngs::StdRng;
@@ -17,7 +18,7 @@ use log::*;
 enum BenchmarkServer<'a> {
     SealPir(PirServer<'a>),
     RaidPir(RaidPirServer<RaidPirData>, u128),
-    HybridPir(HybridPirServer, u128),
+    HybridPir(HybridPirServer, u128, u64, Option<Arc<Vec<u8>>>),
 }
 
 impl BenchmarkServer<'_> {
@@ -74,30 +75,10 @@ impl BenchmarkServer<'_> {
                     russians
                 ), 0)
             },
-            BenchmarkParams::HybridPir{
-                db_size,
-                element_size,
-                raidpir_servers,
-                raidpir_redundancy,
-                raidpir_size,
-                raidpir_russians,
-                sealpir_poly_degree,
-                sealpir_log,
-                sealpir_d
-            } => {
-                let db = Self::setup_db(db_size, element_size);
+            BenchmarkParams::HybridPir(params) => {
+                let db = Self::setup_db(params.db_len, params.element_size);
 
-                BenchmarkServer::HybridPir(HybridPirServer::new(
-                    &db,
-                    id,
-                    raidpir_servers,
-                    raidpir_redundancy,
-                    raidpir_size,
-                    raidpir_russians,
-                    sealpir_poly_degree,
-                    sealpir_log,
-                    sealpir_d
-                ), 0)
+                BenchmarkServer::HybridPir(HybridPirServer::new(&db, id, &params).unwrap(), 0, 0, None)
             }
         }
     }
@@ -108,7 +89,7 @@ impl BenchmarkServer<'_> {
             Self::RaidPir(ref mut server, _seed) => {
                 server.preprocess();
             },
-            Self::HybridPir(ref mut server, _seed) => {
+            Self::HybridPir(ref mut server, _seed, _session, _key) => {
                 server.preprocess();
             },
         }
@@ -152,15 +133,44 @@ impl BenchmarkServer<'_> {
                 }
             },
             ProtocolMessage::HybridPir(hybridpir_msg) => {
-                if let BenchmarkServer::HybridPir(ref mut server, ref mut seed) = self {
-                    if let HybridPirMessage::Hello = hybridpir_msg {
+                if let BenchmarkServer::HybridPir(ref mut server, ref mut seed, ref mut session, ref mut key) = self {
+                    if let HybridPirMessage::Hello(s, params, _) = hybridpir_msg {
+                        if server.check_params(&params).is_err() {
+                            return Some(ProtocolMessage::HybridPir(HybridPirMessage::ParamsMismatch(server.fingerprint())));
+                        }
+                        *session = s;
+                        // Hold on to the key for the rest of the cycle, it
+                        // may be evicted from the cache in the meantime
+                        *key = server.sealpir_key(*session);
+                        if key.is_none() {
+                            return Some(ProtocolMessage::HybridPir(HybridPirMessage::KeyRequired));
+                        }
+                        let t = std::time::Instant::now();
+                        *seed = server.seed();
+                        debug!("Seed time: {:?}", t.elapsed().as_secs_f64() * 1000.0);
+                        Some(ProtocolMessage::HybridPir(HybridPirMessage::Seed(*seed)))
+                    } else if let HybridPirMessage::RegisterKey(sealpir_key) = hybridpir_msg {
+                        *key = match server.register_session_key(*session, sealpir_key) {
+                            Ok(key) => Some(key),
+                            Err(e) => {
+                                error!("{}", e);
+                                return Some(ProtocolMessage::HybridPir(HybridPirMessage::Error {
+                                    code: ErrorCode::Protocol,
+                                    message: e.to_string(),
+                                }));
+                            }
+                        };
                         let t = std::time::Instant::now();
                         *seed = server.seed();
                         debug!("Seed time: {:?}", t.elapsed().as_secs_f64() * 1000.0);
                         Some(ProtocolMessage::HybridPir(HybridPirMessage::Seed(*seed)))
-                    } else if let HybridPirMessage::Query(raidpir_query, sealpir_key, sealpir_query) = hybridpir_msg {
+                    } else if let HybridPirMessage::Query(raidpir_query, sealpir_query) = hybridpir_msg {
                         let t = std::time::Instant::now();
                         let bitvec: BitVec<Lsb0, u8> = BitVec::from_vec(raidpir_query);
+                        let sealpir_key = match key.as_ref() {
+                            Some(key) => key,
+                            None => return Some(ProtocolMessage::HybridPir(HybridPirMessage::KeyRequired)),
+                        };
                         let response = server.response(*seed, &bitvec, &sealpir_key, &sealpir_query);
                         debug!("Response time: {:?}", t.elapsed().as_secs_f64() * 1000.0);
                         Some(ProtocolMessage::HybridPir(HybridPirMessage::Response(response)))
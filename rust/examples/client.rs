@@ -1,18 +1,20 @@
 use hybridpir::client::HybridPirClient;
+use hybridpir::params::HybridPirParams;
 
 fn main() {
     env_logger::init();
 
     let index = std::env::args().nth(1).unwrap().parse().unwrap();
 
-    let size = 1 << 22;
-    let raidpir_servers = 2;
-    let raidpir_redundancy = 2;
-    let raidpir_size = 1 << 12;
+    let params = HybridPirParams::builder(1 << 22, 8)
+        .raidpir_servers(2)
+        .raidpir_redundancy(2)
+        .raidpir_size(1 << 12)
+        .sealpir(2048, 12, 2)
+        .build()
+        .unwrap();
 
-    let client = HybridPirClient::new(size, 8,
-        raidpir_servers, raidpir_redundancy, raidpir_size,
-        2048, 12, 2);
+    let client = HybridPirClient::new(&params).unwrap();
 
     for _i in 0..1 {
         let response = client
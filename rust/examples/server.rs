@@ -1,6 +1,7 @@
 use rand::rngs::StdRng;
 use rand::{RngCore, SeedableRng};
 
+use hybridpir::params::HybridPirParams;
 use hybridpir::server::HybridPirServer;
 
 fn main() {
@@ -11,21 +12,30 @@ fn main() {
     let mut prng = StdRng::seed_from_u64(1234);
 
     let size = 1 << 22;
-    let raidpir_servers = 2;
-    let raidpir_redundancy = 2;
-    let raidpir_size = 1 << 12;
-
-    let mut db: Vec<Vec<u8>> = Vec::with_capacity(size);
-    for _i in 0..size {
-        let mut buffer = vec![0; 8];
-        prng.fill_bytes(&mut buffer);
-        db.push(buffer);
-    }
-    db[size >> 1] = b"deadbeef".to_vec();
-
-    let server = HybridPirServer::new(&db,
-        id, raidpir_servers, raidpir_redundancy, raidpir_size, false,
-        2048, 12, 2);
-
-    server.accept_connections(("0.0.0.0", (7000 + id) as u16)).unwrap();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_servers(2)
+        .raidpir_redundancy(2)
+        .raidpir_size(1 << 12)
+        .sealpir(2048, 12, 2)
+        .build()
+        .unwrap();
+
+    // Serve a file of 8 byte records if given, random data otherwise
+    let server = match std::env::args().nth(2) {
+        Some(path) => HybridPirServer::from_file(path, id, &params).unwrap(),
+        None => {
+            let mut db: Vec<Vec<u8>> = Vec::with_capacity(size);
+            for _i in 0..size {
+                let mut buffer = vec![0; 8];
+                prng.fill_bytes(&mut buffer);
+                db.push(buffer);
+            }
+            db[size >> 1] = b"deadbeef".to_vec();
+
+            HybridPirServer::new(&db, id, &params).unwrap()
+        }
+    };
+
+    server.accept_connections(("0.0.0.0", (7000 + id) as u16)).unwrap().join();
 }
@@ -11,6 +11,7 @@ use sealpir::PirReply;
 use raidpir::client::RaidPirClient;
 use raidpir::types::RaidPirData;
 use crate::client::HybridPirClient;
+use crate::params::HybridPirParams;
 use crate::types::*;
 
 use log::*;
@@ -35,8 +36,8 @@ const DEFAULT_RAIDPIR: BenchmarkParams = BenchmarkParams::RaidPir {
     russians: false
 };
 
-const DEFAULT_HYBRIDPIR: BenchmarkParams = BenchmarkParams::HybridPir {
-    db_size: 1 << 14,
+const DEFAULT_HYBRIDPIR: BenchmarkParams = BenchmarkParams::HybridPir(HybridPirParams {
+    db_len: 1 << 14,
     element_size: 1 << 4,
     raidpir_servers: 2,
     raidpir_redundancy: 2,
@@ -45,7 +46,8 @@ const DEFAULT_HYBRIDPIR: BenchmarkParams = BenchmarkParams::HybridPir {
     sealpir_poly_degree: 2048,
     sealpir_log: 24,
     sealpir_d: 1,
-};
+    variable_length: false,
+});
 
 fn run_query(streams: &mut Vec<TcpStream>, params: &BenchmarkParams) -> Result<(), Error> {
     match params {
@@ -134,24 +136,20 @@ fn run_query(streams: &mut Vec<TcpStream>, params: &BenchmarkParams) -> Result<(
             client.combine(responses.into_iter().map(|r| RaidPirData::new(r)).collect());
             debug!("Decode time: {:?}", t.elapsed().as_secs_f64() * 1000.0);
         },
-        BenchmarkParams::HybridPir {
-            db_size,
-            element_size,
-            raidpir_servers,
-            raidpir_redundancy,
-            raidpir_size,
-            raidpir_russians: _,
-            sealpir_poly_degree,
-            sealpir_log,
-            sealpir_d
-        } => {
-            let index = db_size >> 1;
+        BenchmarkParams::HybridPir(params) => {
+            let index = params.db_len >> 1;
+
+            let client = HybridPirClient::new(params).unwrap();
 
             let seeds = streams
                 .par_iter()
                 .map(|ref mut stream| {
-                    BenchmarkMessage::Protocol(ProtocolMessage::HybridPir(HybridPirMessage::Hello)).write_to(stream)?;
-                    let response = BenchmarkMessage::read_from(stream)?;
+                    BenchmarkMessage::Protocol(ProtocolMessage::HybridPir(HybridPirMessage::Hello(client.session_id(), client.fingerprint(), Vec::new()))).write_to(stream)?;
+                    let mut response = BenchmarkMessage::read_from(stream)?;
+                    if let BenchmarkMessage::Protocol(ProtocolMessage::HybridPir(HybridPirMessage::KeyRequired)) = response {
+                        BenchmarkMessage::Protocol(ProtocolMessage::HybridPir(HybridPirMessage::RegisterKey(client.sealpir_key().clone()))).write_to(stream)?;
+                        response = BenchmarkMessage::read_from(stream)?;
+                    }
                     if let BenchmarkMessage::Protocol(ProtocolMessage::HybridPir(HybridPirMessage::Seed(seed))) = response {
                         Ok(seed)
                     } else {
@@ -161,20 +159,8 @@ fn run_query(streams: &mut Vec<TcpStream>, params: &BenchmarkParams) -> Result<(
                 .with_max_len(1)
                 .collect::<Result<Vec<u128>, Error>>()?;
 
-            let client = HybridPirClient::new(
-                *db_size,
-                *element_size,
-                *raidpir_servers,
-                *raidpir_redundancy,
-                *raidpir_size,
-                *sealpir_poly_degree,
-                *sealpir_log,
-                *sealpir_d
-            );
-
-            let sealpir_key = client.sealpir_key();
             let t = std::time::Instant::now();
-            let (raidpir_queries, sealpir_query) = client.query(index, &seeds);
+            let (raidpir_queries, sealpir_query) = client.query(index, &seeds).unwrap();
 
             debug!("Query size: {:?} (SealPIR)", sealpir_query.query.len());
             debug!("Query size: {:?} (RaidPIR)", raidpir_queries[0].len());
@@ -186,7 +172,6 @@ fn run_query(streams: &mut Vec<TcpStream>, params: &BenchmarkParams) -> Result<(
                 .map(|(ref mut stream, raidpir_query)| {
                     BenchmarkMessage::Protocol(ProtocolMessage::HybridPir(HybridPirMessage::Query(
                         raidpir_query.clone().into_vec(),
-                        sealpir_key.clone(),
                         sealpir_query.clone()
                     ))).write_to(stream)?;
                     let response = BenchmarkMessage::read_from(stream)?;
@@ -202,7 +187,7 @@ fn run_query(streams: &mut Vec<TcpStream>, params: &BenchmarkParams) -> Result<(
             debug!("Response size: {:?}", responses[0].reply.len());
 
             let t = std::time::Instant::now();
-            client.combine(index, responses);
+            client.combine(index, responses).unwrap();
             debug!("Decode time: {:?}", t.elapsed().as_secs_f64() * 1000.0);
         },
     }
@@ -286,20 +271,10 @@ fn run_different_element_counts(streams: &mut Vec<TcpStream>, ns: &Vec<usize>, s
         //if (*n / b) >= 2 * 8 {
         if false {
             let mut params_hybridpir = DEFAULT_HYBRIDPIR.clone();
-            if let BenchmarkParams::HybridPir{
-                ref mut db_size,
-                ref mut element_size,
-                raidpir_servers: _,
-                raidpir_redundancy: _,
-                ref mut raidpir_size,
-                raidpir_russians: _,
-                sealpir_poly_degree: _,
-                sealpir_log: _,
-                sealpir_d: _,
-            } = params_hybridpir {
-                *db_size = *n;
-                *element_size = s;
-                *raidpir_size = n / b;
+            if let BenchmarkParams::HybridPir(ref mut params) = params_hybridpir {
+                params.db_len = *n;
+                params.element_size = s;
+                params.raidpir_size = n / b;
             }
             let time_hybridpir = run_series(streams, params_hybridpir, N).unwrap();
 
@@ -0,0 +1,1191 @@
+//! Async front end for client and server, speaking the same protocol as their
+//! blocking counterparts over tokio. PIR computations are still done by
+//! `HybridPirClient` and `HybridPirServer`, only the transport differs.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use bitvec::prelude::*;
+use futures::future::{select, try_join_all, Either};
+use sealpir::{PirQuery, PirReply};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{watch, Mutex, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::client::{HybridPirClient, Progress};
+use crate::error::HybridPirError;
+use crate::keyword::KeywordLayout;
+use crate::metrics::{Metrics, Phase, Stats};
+use crate::server::{HybridPirServer, ServerConfig, ServerHandle, ServerState, IDLE_TIMEOUT, MAX_BATCH_SIZE};
+use crate::streaming::ResponseParts;
+use crate::types::*;
+
+/// Timeout for messages within a query cycle.
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/**
+ * Stream of `HybridPirMessage`s over an async transport. Incoming data is
+ * buffered until a complete frame has arrived.
+ *
+ * Like `Stream`, messages are sent in the encoding of the last one received.
+ */
+struct Connection<S> {
+    stream: S,
+    buffer: Vec<u8>,
+    limits: SizeLimits,
+    encoding: Encoding,
+    /// Where servers count messages and their sizes.
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    fn new(stream: S, limits: SizeLimits) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            limits,
+            encoding: Encoding::Bincode,
+            metrics: None,
+        }
+    }
+
+    fn with_metrics(self, metrics: Arc<Metrics>) -> Self {
+        Self {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    async fn write(&mut self, message: &HybridPirMessage) -> Result<(), HybridPirError> {
+        self.write_compressed(message, Compression::None).await
+    }
+
+    async fn write_compressed<M: Frame>(&mut self, message: &M, compression: Compression) -> Result<(), HybridPirError> {
+        let mut serialized: Vec<u8> = Vec::new();
+        message.write_frame(&mut serialized, self.encoding, compression)?;
+
+        timeout(MESSAGE_TIMEOUT, self.stream.write_all(&serialized)).await.map_err(Error::from)??;
+        timeout(MESSAGE_TIMEOUT, self.stream.flush()).await.map_err(Error::from)??;
+
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.sent(message.message_type(), serialized.len() as u64);
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Send a response, streamed in parts if it's too large for a single
+     * message. Every part gets the full timeout.
+     */
+    async fn write_response(&mut self,
+        response: HybridPirMessage,
+        compression: Compression,
+        part_size: usize
+    ) -> Result<(), HybridPirError> {
+        let (first, parts) = ResponseParts::split(response, self.encoding, part_size)?;
+        self.write_compressed(&first, compression).await?;
+
+        for part in parts.into_iter().flatten() {
+            self.write_compressed(&part?, compression).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn read_timeout(&mut self, duration: Duration) -> Result<HybridPirMessage, HybridPirError> {
+        let mut chunk = vec![0; 1 << 16];
+
+        loop {
+            // Wait for the whole frame before decoding, but refuse oversized
+            // ones as soon as the header is in.
+            if self.buffer.len() >= HEADER_SIZE {
+                let length = HybridPirMessage::frame_length(&self.buffer[..HEADER_SIZE], &self.limits)?;
+
+                if self.buffer.len() >= HEADER_SIZE + length {
+                    let (message, encoding) = HybridPirMessage::decode(
+                        &self.buffer[..HEADER_SIZE],
+                        &self.buffer[HEADER_SIZE..HEADER_SIZE + length],
+                        &self.limits)?;
+
+                    if let Some(metrics) = self.metrics.as_ref() {
+                        metrics.received(message.message_type(), (HEADER_SIZE + length) as u64);
+                    }
+
+                    self.buffer.drain(..HEADER_SIZE + length);
+                    self.encoding = encoding;
+                    return Ok(message);
+                }
+            }
+
+            let n = timeout(duration, self.stream.read(&mut chunk))
+                .await
+                .map_err(|_| Error::new(ErrorKind::TimedOut, "Timed out waiting for message."))??;
+
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed.").into());
+            }
+
+            self.buffer.extend_from_slice(&chunk[..n]);
+
+            // Read larger chunks for large messages
+            if n == chunk.len() && chunk.len() < (1 << 22) {
+                chunk.resize(chunk.len() * 2, 0);
+            }
+        }
+    }
+
+    async fn read(&mut self) -> Result<HybridPirMessage, HybridPirError> {
+        self.read_timeout(MESSAGE_TIMEOUT).await
+    }
+
+    async fn shutdown(&mut self) {
+        self.stream.shutdown().await.ok();
+    }
+}
+
+/**
+ * Async counterpart of `HybridPirClient`, wrapping one for generating queries
+ * and decoding responses. Only speaks plain TCP, servers set up with
+ * `HybridPirClient::set_tls` are refused rather than contacted without TLS.
+ */
+pub struct AsyncHybridPirClient<'a> {
+    client: HybridPirClient<'a>,
+    connections: Mutex<HashMap<SocketAddr, Connection<TcpStream>>>,
+}
+
+impl<'a> AsyncHybridPirClient<'a> {
+    pub fn new(client: HybridPirClient<'a>) -> Self {
+        Self {
+            client,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * The underlying client, used for generating queries and decoding
+     * responses.
+     */
+    pub fn client(&self) -> &HybridPirClient<'a> {
+        &self.client
+    }
+
+    async fn connect(&self, target: &SocketAddr) -> Result<Connection<TcpStream>, HybridPirError> {
+        // Rather than send queries and tokens in the clear
+        if self.client.uses_tls(target) {
+            return Err(HybridPirError::InvalidParameters(
+                format!("TLS is set up for {}, but not supported by the async client.", target)));
+        }
+
+        let stream = timeout(MESSAGE_TIMEOUT, TcpStream::connect(target))
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "Timed out connecting."))??;
+        stream.set_nodelay(true)?;
+        Ok(Connection::new(stream, *self.client.size_limits()))
+    }
+
+    /**
+     * Start a new query cycle on the given connection and return the server's
+     * answer, authenticating and registering our SealPIR key first if the
+     * server asks for it. Tokens are taken from the wrapped client, see
+     * `HybridPirClient::set_token`, and so are compression preferences and
+     * the encoding. Error replies are returned as errors.
+     */
+    async fn hello(&self,
+        connection: &mut Connection<TcpStream>,
+        hello: &HybridPirMessage
+    ) -> Result<(HybridPirMessage, Compression), HybridPirError> {
+        connection.encoding = self.client.encoding();
+        connection.write(hello).await?;
+
+        let mut response = connection.read().await?.into_result()?;
+
+        if let HybridPirMessage::ParamsMismatch(remote) = response {
+            return Err(HybridPirError::ParamsMismatch {
+                peer: None,
+                local: self.client.fingerprint(),
+                remote,
+            });
+        }
+
+        // Only sent if we asked for compression
+        let mut compression = Compression::None;
+        if let HybridPirMessage::UseCompression(c) = response {
+            if !self.client.compression().contains(&c) && c != Compression::None {
+                return Err(HybridPirError::protocol(format!("Server chose compression {:?}, which we didn't offer.", c)));
+            }
+
+            compression = c;
+            response = connection.read().await?.into_result()?;
+        }
+
+        // Only asked once per connection, before anything else
+        if response == HybridPirMessage::AuthRequired {
+            let message = HybridPirMessage::Authenticate(self.client.token(&connection.stream.peer_addr()?)?.clone());
+            connection.write(&message).await?;
+
+            response = connection.read().await?.into_result()?;
+        }
+
+        // Server doesn't know our key yet, upload it once
+        if response == HybridPirMessage::KeyRequired {
+            let message = HybridPirMessage::RegisterKey(self.client.sealpir_key().clone());
+            connection.write_compressed(&message, compression).await?;
+
+            response = connection.read().await?.into_result()?;
+        }
+
+        Ok((response, compression))
+    }
+
+    async fn resolve<A: ToSocketAddrs>(&self, targets: &[A]) -> Result<Vec<SocketAddr>, HybridPirError> {
+        if targets.len() != self.client.servers() {
+            return Err(HybridPirError::InvalidParameters(
+                format!("Expected {} servers, got {}.", self.client.servers(), targets.len())));
+        }
+
+        try_join_all(targets.iter().enumerate().map(|(i, target)| async move {
+            lookup_host(target)
+                .await
+                .map_err(|e| HybridPirError::Resolution(format!("server {}: {}", i, e)))?
+                .next()
+                .ok_or(HybridPirError::Resolution(format!("server {}: no addresses found", i)))
+        })).await
+    }
+
+    /**
+     * Send the hello message to every server, reusing connections left open
+     * by previous queries where possible. Returns the connections, and each
+     * server's answer and compression.
+     */
+    async fn start_cycle(&self,
+        addresses: &[SocketAddr],
+        hello: &HybridPirMessage
+    ) -> Result<(Vec<Connection<TcpStream>>, Vec<(HybridPirMessage, Compression)>), HybridPirError> {
+        let pooled: Vec<Option<Connection<TcpStream>>> = {
+            let mut connections = self.connections.lock().await;
+            addresses.iter().map(|a| connections.remove(a)).collect()
+        };
+
+        let results = try_join_all(addresses
+            .iter()
+            .zip(pooled.into_iter())
+            .map(|(target, connection)| async move {
+                // The server may have closed an idle connection in the
+                // meantime, in that case just start over on a new one. Any
+                // other error is the server's answer, and asking again on a
+                // new connection won't change it.
+                if let Some(mut connection) = connection {
+                    match self.hello(&mut connection, hello).await {
+                        Ok(response) => return Ok((connection, response)),
+                        Err(e) if e.is_disconnect() => debug!("[{:?}] Reconnecting ({})...", target, e),
+                        Err(e) => return Err(e.with_peer(*target)),
+                    }
+                }
+
+                let mut connection = self.connect(target)
+                    .await
+                    .map_err(|e| e.with_peer(*target))?;
+                let response = self.hello(&mut connection, hello)
+                    .await
+                    .map_err(|e| e.with_peer(*target))?;
+                Ok::<_, HybridPirError>((connection, response))
+            })).await?;
+
+        Ok(results.into_iter().unzip())
+    }
+
+    /**
+     * Keep connections open for the next query.
+     */
+    async fn end_cycle(&self, addresses: Vec<SocketAddr>, connections: Vec<Connection<TcpStream>>) {
+        self.connections.lock().await.extend(addresses.into_iter().zip(connections));
+    }
+
+    /**
+     * Read the response to a query, putting it back together if the server
+     * streams it. `shape` is the number of replies per chunk for batch
+     * queries, and None for single ones.
+     */
+    async fn read_response(&self,
+        connection: &mut Connection<TcpStream>,
+        target: SocketAddr,
+        shape: Option<&[usize]>
+    ) -> Result<HybridPirMessage, HybridPirError> {
+        let sizes = match connection.read().await.and_then(HybridPirMessage::into_result)? {
+            HybridPirMessage::ResponseStart(sizes) => sizes,
+            m => return Ok(m),
+        };
+
+        let mut assembler = self.client.reply_assembler(sizes, shape, connection.encoding)?;
+        let mut replies: Vec<PirReply> = Vec::new();
+
+        while !assembler.is_done() {
+            let part = match connection.read().await.and_then(HybridPirMessage::into_result)? {
+                HybridPirMessage::ResponsePart(part) => part,
+                m => return Err(HybridPirError::unexpected(&m)),
+            };
+
+            replies.extend(assembler.push(part)?.map(|(_, reply)| reply));
+
+            self.client.report_progress(Progress {
+                peer: target,
+                received: assembler.received(),
+                total: assembler.total(),
+            });
+        }
+
+        Ok(match shape {
+            None => HybridPirMessage::Response(replies.remove(0)),
+            Some(shape) => {
+                let mut replies = replies.into_iter();
+
+                HybridPirMessage::BatchResponse(shape
+                    .iter()
+                    .map(|n| replies.by_ref().take(*n).collect())
+                    .collect())
+            }
+        })
+    }
+
+    pub async fn send_query<A: ToSocketAddrs>(&self, targets: &[A], index: usize) -> Result<Vec<u8>, HybridPirError> {
+        let addresses = self.resolve(targets).await?;
+
+        let hello = HybridPirMessage::Hello(self.client.session_id(), self.client.fingerprint(), self.client.compression().to_vec());
+        let (mut connections, responses) = self.start_cycle(&addresses, &hello).await?;
+        let (responses, compression): (Vec<HybridPirMessage>, Vec<Compression>) = responses.into_iter().unzip();
+
+        let seeds: Vec<u128> = addresses
+            .iter()
+            .zip(responses.into_iter())
+            .map(|(target, response)| match response {
+                HybridPirMessage::Seed(s) => Ok(s),
+                m => Err(HybridPirError::unexpected(&m).with_peer(*target))
+            })
+            .collect::<Result<Vec<u128>, HybridPirError>>()?;
+
+        let (raidpir_queries, sealpir_query) = self.client.query(index, &seeds)?;
+
+        let responses: Vec<PirReply> = try_join_all(connections
+            .iter_mut()
+            .zip(addresses.iter().zip(raidpir_queries.into_iter()).zip(compression.into_iter()))
+            .map(|(connection, ((target, raidpir_query), compression))| {
+                let sealpir_query = &sealpir_query;
+
+                async move {
+                    let message = QueryRef {
+                        raidpir_query: raidpir_query.as_slice(),
+                        sealpir_query,
+                    };
+                    connection.write_compressed(&message, compression).await.map_err(|e| e.with_peer(*target))?;
+
+                    match self.read_response(connection, *target, None).await.map_err(|e| e.with_peer(*target))? {
+                        HybridPirMessage::Response(r) => Ok(r),
+                        m => Err(HybridPirError::unexpected(&m).with_peer(*target))
+                    }
+                }
+            })).await?;
+
+        self.end_cycle(addresses, connections).await;
+
+        self.client.combine(index, responses)
+    }
+
+    /**
+     * Retrieve several elements in a single round trip. Elements are returned
+     * in the order of `indices`. This reveals as much as
+     * `HybridPirClient::send_query_batch`, including its padding.
+     */
+    pub async fn send_query_batch<A: ToSocketAddrs>(&self, targets: &[A], indices: &[usize]) -> Result<Vec<Vec<u8>>, HybridPirError> {
+        self.client.check_batch(indices)?;
+
+        let addresses = self.resolve(targets).await?;
+
+        if indices.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let count = self.client.batch_seeds(indices);
+        let shape = self.client.batch_shape(indices);
+
+        let hello = HybridPirMessage::BatchHello(self.client.session_id(), self.client.fingerprint(), count as u32,
+            self.client.compression().to_vec());
+        let (mut connections, responses) = self.start_cycle(&addresses, &hello).await?;
+        let (responses, compression): (Vec<HybridPirMessage>, Vec<Compression>) = responses.into_iter().unzip();
+
+        let seeds: Vec<Vec<u128>> = addresses
+            .iter()
+            .zip(responses.into_iter())
+            .map(|(target, response)| match response {
+                HybridPirMessage::Seeds(s) if s.len() == count => Ok(s),
+                m => Err(HybridPirError::unexpected(&m).with_peer(*target))
+            })
+            .collect::<Result<Vec<Vec<u128>>, HybridPirError>>()?;
+
+        let (raidpir_queries, sealpir_queries) = self.client.query_batch(indices, &seeds)?;
+
+        let responses: Vec<Vec<Vec<PirReply>>> = try_join_all(connections
+            .iter_mut()
+            .zip(addresses.iter().zip(raidpir_queries.into_iter()).zip(compression.into_iter()))
+            .map(|(connection, ((target, raidpir_queries), compression))| {
+                let message = HybridPirMessage::BatchQuery(raidpir_queries
+                    .into_iter()
+                    .zip(sealpir_queries.iter())
+                    .map(|(raidpir_query, sealpir_queries)| ChunkQuery {
+                        raidpir_query: raidpir_query.into_vec(),
+                        sealpir_queries: sealpir_queries.clone(),
+                    })
+                    .collect());
+
+                let shape = &shape;
+
+                async move {
+                    connection.write_compressed(&message, compression).await.map_err(|e| e.with_peer(*target))?;
+
+                    match self.read_response(connection, *target, Some(shape)).await.map_err(|e| e.with_peer(*target))? {
+                        HybridPirMessage::BatchResponse(r) if r.len() == count => Ok(r),
+                        m => Err(HybridPirError::unexpected(&m).with_peer(*target))
+                    }
+                }
+            })).await?;
+
+        self.end_cycle(addresses, connections).await;
+
+        self.client.combine_batch(indices, responses)
+    }
+
+    /**
+     * Look up a key in a database laid out with `layout`, see
+     * `HybridPirClient::send_keyword_query`.
+     */
+    pub async fn send_keyword_query<A: ToSocketAddrs>(&self,
+        targets: &[A],
+        layout: &KeywordLayout,
+        key: &[u8]
+    ) -> Result<Option<Vec<u8>>, HybridPirError> {
+        let params = self.client.params();
+        if layout.buckets != params.db_len
+            || layout.element_size() != params.element_size
+            || params.variable_length
+        {
+            return Err(HybridPirError::InvalidParameters(
+                "Keyword layout doesn't match the database.".into()));
+        }
+
+        let indices = layout.indices(key);
+
+        let mut records = Vec::with_capacity(indices.len());
+        for index in indices {
+            records.push(self.send_query(targets, index).await?);
+        }
+
+        Ok(layout.find(key, &records))
+    }
+
+    /**
+     * Close all connections kept open between queries.
+     */
+    pub async fn close(&self) {
+        let connections: Vec<(SocketAddr, Connection<TcpStream>)> = self.connections
+            .lock()
+            .await
+            .drain()
+            .collect();
+
+        for (target, mut connection) in connections {
+            debug!("[{:?}] Closing connection...", target);
+
+            connection.write(&HybridPirMessage::Close).await.ok();
+            connection.shutdown().await;
+        }
+    }
+}
+
+/**
+ * State shared by all tasks serving clients on one address.
+ */
+#[derive(Debug)]
+struct AsyncServerState {
+    shared: ServerState,
+    /// Set once the server is shutting down.
+    stop: watch::Sender<bool>,
+    /// Connections being served or waiting for their turn.
+    slots: Arc<Semaphore>,
+    /// Connections being served.
+    active: Semaphore,
+    /// PIR work running on the blocking pool. This outlives the connection
+    /// that started it if that one goes away mid-query, so it's counted apart.
+    blocking: Arc<Semaphore>,
+}
+
+impl AsyncServerState {
+    fn new(config: &ServerConfig) -> Self {
+        Self {
+            shared: ServerState::new(config),
+            stop: watch::channel(false).0,
+            slots: Arc::new(Semaphore::new(Self::slots(config) as usize)),
+            active: Semaphore::new(config.max_connections),
+            blocking: Arc::new(Semaphore::new(config.max_connections)),
+        }
+    }
+
+    /**
+     * Run PIR work on the blocking pool, but never more than `max_connections`
+     * at once. A query that panics there never hands its SealPIR server back
+     * to the cache, so there's nothing to clean up afterwards.
+     */
+    async fn run_blocking<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        R: Send + 'static,
+        F: FnOnce() -> R + Send + 'static
+    {
+        let permit = self.blocking.clone().acquire_owned().await
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+        tokio::task::spawn_blocking(move || {
+            let result = f();
+            drop(permit);
+            result
+        }).await.map_err(Error::from)
+    }
+
+    fn slots(config: &ServerConfig) -> u32 {
+        (config.max_connections + config.max_pending).min(u32::MAX as usize) as u32
+    }
+
+    fn config(&self) -> &ServerConfig {
+        &self.shared.config
+    }
+
+    fn metrics(&self) -> &Arc<Metrics> {
+        &self.shared.metrics
+    }
+
+    /**
+     * Resolves once the server is shutting down.
+     */
+    async fn stopped(&self) {
+        let mut stop = self.stop.subscribe();
+
+        // The sender lives as long as the state itself, so this never fails
+        while !*stop.borrow_and_update() {
+            if stop.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/**
+ * Async counterpart of `HybridPirServer::accept_connections`, taking the same
+ * `ServerConfig` and answering clients the same way, except that TLS isn't
+ * supported. Connections are served on tokio tasks, PIR computations are
+ * moved to its blocking pool.
+ */
+#[derive(Debug, Clone)]
+pub struct AsyncHybridPirServer {
+    server: HybridPirServer,
+}
+
+impl AsyncHybridPirServer {
+    pub fn new(server: HybridPirServer) -> Self {
+        Self {
+            server,
+        }
+    }
+
+    pub fn server(&self) -> &HybridPirServer {
+        &self.server
+    }
+
+    /**
+     * Start serving clients on the given address in the background. Binding
+     * to port 0 picks a free port, which `AsyncServerHandle::local_addr`
+     * reports.
+     */
+    pub async fn accept_connections<A: ToSocketAddrs>(self, addr: A) -> Result<AsyncServerHandle, HybridPirError> {
+        self.accept_connections_with(addr, &ServerConfig::default()).await
+    }
+
+    /**
+     * Start serving clients on the given address, turning them away once the
+     * limits in `config` are reached. Like the blocking server, up to
+     * `max_connections` are served at a time, with up to `max_pending` more
+     * waiting for their turn.
+     *
+     * Fails if `config` asks for TLS, rather than serving in plaintext.
+     */
+    pub async fn accept_connections_with<A: ToSocketAddrs>(self,
+        addr: A,
+        config: &ServerConfig
+    ) -> Result<AsyncServerHandle, HybridPirError> {
+        if config.max_connections == 0 {
+            return Err(HybridPirError::InvalidParameters("At least one connection must be allowed.".into()));
+        }
+
+        #[cfg(feature = "tls")]
+        {
+            if config.tls.is_some() {
+                return Err(HybridPirError::InvalidParameters("The async server doesn't support TLS.".into()));
+            }
+        }
+
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let state = Arc::new(AsyncServerState::new(config));
+
+        let mut threads = Vec::with_capacity(config.preprocess_threads + 1);
+
+        let metrics_addr = match config.metrics_addr {
+            Some(addr) => {
+                let listener = std::net::TcpListener::bind(addr)?;
+                let metrics_addr = listener.local_addr()?;
+                let server = self.server.clone();
+                let state = state.clone();
+
+                debug!("Serving metrics on {:?}...", metrics_addr);
+
+                threads.push(std::thread::Builder::new()
+                    .name("hybridpir-metrics".into())
+                    .spawn(move || server.serve_metrics(listener, &state.shared))?);
+
+                Some(metrics_addr)
+            },
+            None => None,
+        };
+
+        if config.queue_depth > 0 {
+            for i in 0..config.preprocess_threads {
+                let server = self.server.clone();
+                let state = state.clone();
+
+                threads.push(std::thread::Builder::new()
+                    .name(format!("hybridpir-preprocess-{}", i))
+                    .spawn(move || server.refill(&state.shared))?);
+            }
+        }
+
+        debug!("Listening on {:?}...", local_addr);
+
+        let acceptor = tokio::spawn(self.clone().accept(listener, state.clone()));
+
+        Ok(AsyncServerHandle {
+            server: self.server,
+            local_addr,
+            metrics_addr,
+            state,
+            tasks: Mutex::new(Some((acceptor, threads))),
+        })
+    }
+
+    /**
+     * Hand every incoming connection to a task of its own, until the server
+     * shuts down.
+     */
+    async fn accept(self, listener: TcpListener, state: Arc<AsyncServerState>) {
+        loop {
+            let accepted = match select(Box::pin(listener.accept()), Box::pin(state.stopped())).await {
+                Either::Left((accepted, _)) => accepted,
+                Either::Right(_) => break,
+            };
+
+            match accepted {
+                Ok((stream, peer)) => match state.slots.clone().try_acquire_owned() {
+                    Ok(slot) => {
+                        let server = self.clone();
+                        let state = state.clone();
+
+                        tokio::spawn(async move {
+                            server.work(stream, peer, &state).await;
+                            drop(slot);
+                        });
+                    },
+                    Err(_) => Self::reject(stream, peer, &state).await,
+                },
+                Err(e) => {
+                    error!("{}", e);
+                }
+            }
+        }
+
+        debug!("Stopped listening on {:?}.", listener.local_addr().ok());
+    }
+
+    /**
+     * Serve a connection once it's its turn. Connections still waiting when
+     * the server shuts down never get served.
+     */
+    async fn work(&self, stream: TcpStream, peer: SocketAddr, state: &AsyncServerState) {
+        let permit = match select(Box::pin(state.active.acquire()), Box::pin(state.stopped())).await {
+            Either::Left((Ok(permit), _)) if !*state.stop.borrow() => permit,
+            _ => {
+                Self::reject(stream, peer, state).await;
+                return;
+            }
+        };
+
+        state.metrics().connection_opened();
+
+        if let Err(e) = self.serve(stream, peer, state).await {
+            state.metrics().error(&e);
+            error!("{}", e.with_peer(peer));
+        }
+
+        state.metrics().connection_closed();
+
+        drop(permit);
+    }
+
+    /**
+     * Tell a client we can't take any more connections right now, without
+     * waiting around for a slow one.
+     */
+    async fn reject(stream: TcpStream, peer: SocketAddr, state: &AsyncServerState) {
+        warn!("[{:?}] Too many connections, rejecting.", peer);
+
+        state.metrics().connection_rejected();
+
+        // Nothing was read yet, so this goes out in the default encoding
+        let mut connection = Connection::new(stream, SizeLimits::default())
+            .with_metrics(state.metrics().clone());
+        let reply = HybridPirMessage::Error {
+            code: ErrorCode::Overloaded,
+            message: "Too many connections.".to_string(),
+        };
+
+        timeout(Duration::from_millis(100), connection.write(&reply)).await.ok();
+        connection.shutdown().await;
+    }
+
+    /**
+     * Serve a single client on the current task, refilling the RaidPIR queue
+     * after every query.
+     */
+    pub async fn handle_connection(&self, stream: TcpStream) -> Result<(), HybridPirError> {
+        let peer = stream.peer_addr()?;
+
+        let state = AsyncServerState::new(&ServerConfig {
+            queue_depth: 0,
+            ..ServerConfig::default()
+        });
+
+        self.serve(stream, peer, &state).await.map_err(|e| e.with_peer(peer))
+    }
+
+    async fn serve(&self, stream: TcpStream, peer: SocketAddr, state: &AsyncServerState) -> Result<(), HybridPirError> {
+        stream.set_nodelay(true)?;
+
+        let mut connection = Connection::new(stream, state.config().size_limits)
+            .with_metrics(state.metrics().clone());
+
+        debug!("[{:?}] Accepting connection, waiting for hello...", peer);
+
+        let result = self.serve_cycles(&mut connection, peer, state).await;
+
+        match result {
+            Ok(queries) => debug!("[{:?}] Closing connection after {} queries.", peer, queries),
+            Err(ref e) => {
+                if let Some(reply) = self.server.error_reply(e) {
+                    connection.write(&reply).await.ok();
+                }
+            },
+        }
+
+        connection.shutdown().await;
+
+        result.map(|_| ())
+    }
+
+    /**
+     * Answer queries until the client closes the connection or the server
+     * shuts down, returning how many there were.
+     */
+    async fn serve_cycles(&self,
+        connection: &mut Connection<TcpStream>,
+        peer: SocketAddr,
+        state: &AsyncServerState
+    ) -> Result<usize, HybridPirError> {
+        let mut queries = 0;
+
+        // Set on the first hello, if the server wants authentication
+        let mut authenticated = false;
+        let mut client = None;
+
+        loop {
+            if !Self::await_hello(connection, state).await? {
+                break;
+            }
+
+            let t0 = Instant::now();
+
+            let (session, params, batch, offered) = match connection.read().await {
+                Ok(HybridPirMessage::Hello(session, params, offered)) => (session, params, None, offered),
+                Ok(HybridPirMessage::BatchHello(session, params, count, offered)) => (session, params, Some(count as usize), offered),
+                Ok(HybridPirMessage::Close) => break,
+                Ok(m) => {
+                    return Err(HybridPirError::unexpected(&m));
+                },
+                // Client went away in between queries
+                Err(ref e) if e.is_disconnect() => break,
+                Err(e) => {
+                    return Err(e);
+                }
+            };
+
+            state.metrics().record(Phase::Hello, t0.elapsed());
+
+            self.server.check_params(&params)?;
+
+            // Clients not asking for compression don't need to be told
+            let compression = if offered.is_empty() {
+                Compression::None
+            } else {
+                let compression = Compression::negotiate(&offered, &state.config().compression);
+                connection.write(&HybridPirMessage::UseCompression(compression)).await?;
+                compression
+            };
+
+            if !authenticated {
+                client = Self::authenticate(connection, peer, state).await?;
+                authenticated = true;
+            }
+
+            let cost = batch.unwrap_or(1);
+            if !state.shared.limiter.check(peer.ip(), state.config().ip_limit, client.as_deref(), state.config().client_limit, cost) {
+                warn!("[{:?}] Query quota of {:?} exceeded, rejecting query.", peer, client);
+                return Err(HybridPirError::RateLimited { peer: None });
+            }
+
+            let sealpir_key = self.session_key(connection, session, state).await?;
+
+            // Answer the whole cycle from the database as it is right now
+            let server = self.server.snapshot();
+
+            match batch {
+                None => Self::handle_query(connection, peer, server, session, sealpir_key, compression, state).await?,
+                Some(count) => Self::handle_batch_query(connection, peer, server, session, sealpir_key, count, compression, state).await?,
+            }
+
+            debug!("[{:?}] Answered query ({:.4}ms).",
+                peer, t0.elapsed().as_secs_f64() * 1000.0);
+
+            queries += 1;
+
+            // Without background preprocessing, rebuild the RaidPIR queue
+            // while the client is busy decoding.
+            if state.config().queue_depth == 0 {
+                let server = self.server.clone();
+                state.run_blocking(move || server.preprocess()).await?;
+            }
+        }
+
+        Ok(queries)
+    }
+
+    /**
+     * Wait for the client to start its next query cycle. Returns false if the
+     * client idled out or the server is shutting down, both of which just
+     * mean the connection should be closed.
+     *
+     * Clients don't send anything before they got the previous response, so
+     * there is nothing buffered in between cycles.
+     */
+    async fn await_hello(connection: &Connection<TcpStream>, state: &AsyncServerState) -> Result<bool, HybridPirError> {
+        if !connection.buffer.is_empty() {
+            return Ok(true);
+        }
+
+        let readable = timeout(IDLE_TIMEOUT, connection.stream.readable());
+
+        match select(Box::pin(readable), Box::pin(state.stopped())).await {
+            Either::Left((Ok(result), _)) => result.map(|_| true).map_err(HybridPirError::from),
+            Either::Left((Err(_), _)) | Either::Right(_) => Ok(false),
+        }
+    }
+
+    /**
+     * Have the client present its token, if the server is set up to check
+     * them, and return the identity the authorizer associates it with.
+     */
+    async fn authenticate(
+        connection: &mut Connection<TcpStream>,
+        peer: SocketAddr,
+        state: &AsyncServerState
+    ) -> Result<Option<String>, HybridPirError> {
+        let authorizer = match state.config().authorizer.as_ref() {
+            Some(authorizer) => authorizer,
+            None => return Ok(None),
+        };
+
+        connection.write(&HybridPirMessage::AuthRequired).await?;
+
+        let token = match connection.read().await? {
+            HybridPirMessage::Authenticate(token) => Ok(token),
+            m => Err(HybridPirError::unexpected(&m))
+        }?;
+
+        let client = state.shared.authorize(authorizer, peer, &token)?;
+        debug!("[{:?}] Authenticated as {}.", peer, client);
+
+        Ok(Some(client))
+    }
+
+    async fn session_key<S: AsyncRead + AsyncWrite + Unpin>(&self,
+        connection: &mut Connection<S>,
+        session: u64,
+        state: &AsyncServerState
+    ) -> Result<Arc<Vec<u8>>, HybridPirError> {
+        if let Some(key) = self.server.sealpir_key(session) {
+            return Ok(key);
+        }
+
+        let tk = Instant::now();
+
+        connection.write(&HybridPirMessage::KeyRequired).await?;
+
+        let key = match connection.read().await? {
+            HybridPirMessage::RegisterKey(key) => self.server.register_session_key(session, key),
+            m => Err(HybridPirError::unexpected(&m))
+        }?;
+
+        state.metrics().record(Phase::KeyRegistration, tk.elapsed());
+
+        Ok(key)
+    }
+
+    /**
+     * Take seeds from the RaidPIR queue on the blocking pool, since they may
+     * have to be computed or waited for. If they're refused because the queue
+     * ran dry, the client is told the server is busy.
+     */
+    async fn take_seeds(
+        server: &HybridPirServer,
+        peer: SocketAddr,
+        count: usize,
+        state: &AsyncServerState
+    ) -> Result<Vec<u128>, HybridPirError> {
+        let server = server.clone();
+        let policy = state.config().when_queue_empty;
+
+        let seeds = state.run_blocking(move || server.queued_seeds(count, policy)).await?;
+
+        match seeds {
+            Some(seeds) => Ok(seeds),
+            None => {
+                warn!("[{:?}] RaidPIR queue empty, rejecting query.", peer);
+                Err(HybridPirError::Overloaded { peer: None })
+            }
+        }
+    }
+
+    async fn handle_query<S: AsyncRead + AsyncWrite + Unpin>(
+        connection: &mut Connection<S>,
+        peer: SocketAddr,
+        server: HybridPirServer,
+        session: u64,
+        sealpir_key: Arc<Vec<u8>>,
+        compression: Compression,
+        state: &AsyncServerState
+    ) -> Result<(), HybridPirError> {
+        let t1 = Instant::now();
+
+        let seed = Self::take_seeds(&server, peer, 1, state).await?[0];
+        connection.write(&HybridPirMessage::Seed(seed)).await?;
+
+        state.metrics().record(Phase::Seed, t1.elapsed());
+
+        let t2 = Instant::now();
+
+        let (raidpir_query, sealpir_query) = match connection.read().await? {
+            HybridPirMessage::Query(a, b) => Ok((a, b)),
+            m => Err(HybridPirError::unexpected(&m))
+        }?;
+
+        let raidpir_query: BitVec<Lsb0, u8> = BitVec::from_vec(raidpir_query);
+        server.check_query(&raidpir_query, std::slice::from_ref(&sealpir_query))?;
+
+        state.metrics().record(Phase::Query, t2.elapsed());
+
+        let t3 = Instant::now();
+
+        let response = state.run_blocking(move || {
+            server.session_response(seed, &raidpir_query, session, &sealpir_key, &sealpir_query)
+        }).await?;
+
+        state.metrics().record(Phase::Response, t3.elapsed());
+
+        let t4 = Instant::now();
+
+        connection.write_response(HybridPirMessage::Response(response), compression, state.config().response_part_size).await?;
+
+        state.metrics().record(Phase::Send, t4.elapsed());
+
+        Ok(())
+    }
+
+    async fn handle_batch_query<S: AsyncRead + AsyncWrite + Unpin>(
+        connection: &mut Connection<S>,
+        peer: SocketAddr,
+        server: HybridPirServer,
+        session: u64,
+        sealpir_key: Arc<Vec<u8>>,
+        count: usize,
+        compression: Compression,
+        state: &AsyncServerState
+    ) -> Result<(), HybridPirError> {
+        if count == 0 || count > MAX_BATCH_SIZE {
+            return Err(HybridPirError::protocol(format!("Invalid batch size {}.", count)));
+        }
+
+        let t1 = Instant::now();
+
+        let seeds = Self::take_seeds(&server, peer, count, state).await?;
+        connection.write(&HybridPirMessage::Seeds(seeds.clone())).await?;
+
+        state.metrics().record(Phase::Seed, t1.elapsed());
+
+        let t2 = Instant::now();
+
+        let chunk_queries = match connection.read().await? {
+            HybridPirMessage::BatchQuery(q) if q.len() == count => Ok(q),
+            m => Err(HybridPirError::unexpected(&m))
+        }?;
+
+        let (raidpir_queries, sealpir_queries): (Vec<BitVec<Lsb0, u8>>, Vec<Vec<PirQuery>>) = chunk_queries
+            .into_iter()
+            .map(|q| (BitVec::from_vec(q.raidpir_query), q.sealpir_queries))
+            .unzip();
+
+        for (raidpir_query, sealpir_queries) in raidpir_queries.iter().zip(sealpir_queries.iter()) {
+            server.check_query(raidpir_query, sealpir_queries)?;
+        }
+
+        state.metrics().record(Phase::Query, t2.elapsed());
+
+        let t3 = Instant::now();
+
+        let response = state.run_blocking(move || {
+            server.session_response_batch(&seeds, &raidpir_queries, session, &sealpir_key, &sealpir_queries)
+        }).await??;
+
+        state.metrics().record(Phase::Response, t3.elapsed());
+
+        let t4 = Instant::now();
+
+        connection.write_response(HybridPirMessage::BatchResponse(response), compression, state.config().response_part_size).await?;
+
+        state.metrics().record(Phase::Send, t4.elapsed());
+
+        Ok(())
+    }
+}
+
+/**
+ * An async server running in the background, as started by
+ * `AsyncHybridPirServer::accept_connections`. Dropping the handle tells the
+ * server to stop, but unlike `shutdown`, doesn't wait for it.
+ */
+pub struct AsyncServerHandle {
+    server: HybridPirServer,
+    local_addr: SocketAddr,
+    metrics_addr: Option<SocketAddr>,
+    state: Arc<AsyncServerState>,
+    tasks: Mutex<Option<(JoinHandle<()>, Vec<std::thread::JoinHandle<()>>)>>,
+}
+
+impl AsyncServerHandle {
+    /**
+     * Address the server is listening on.
+     */
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /**
+     * Address metrics are served on, if enabled in `ServerConfig`.
+     */
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /**
+     * Number of RaidPIR seeds currently precomputed, see `ServerConfig`.
+     */
+    pub fn queue_depth(&self) -> usize {
+        self.server.queue_depth()
+    }
+
+    /**
+     * Everything recorded since the server was started.
+     */
+    pub fn stats(&self) -> Stats {
+        self.state.metrics().stats(self.queue_depth())
+    }
+
+    /**
+     * Stop accepting connections, let queries already in progress finish and
+     * wait for the server to stop. Idle connections are closed right away.
+     */
+    pub async fn shutdown(&self) {
+        if let Some(addr) = self.stop() {
+            tokio::task::spawn_blocking(move || ServerHandle::wake(addr)).await.ok();
+        }
+
+        self.join().await;
+    }
+
+    /**
+     * Tell the server to stop, returning the address of the metrics listener
+     * if it still has to be woken up. It only notices once it gets a
+     * connection.
+     */
+    fn stop(&self) -> Option<SocketAddr> {
+        self.state.shared.stopping.store(true, Ordering::SeqCst);
+
+        if self.state.stop.send_replace(true) {
+            return None;
+        }
+
+        debug!("Shutting down server on {:?}...", self.local_addr);
+        self.metrics_addr
+    }
+
+    /**
+     * Wait for the server to stop, which only happens after `shutdown`.
+     */
+    pub async fn join(&self) {
+        let tasks = self.tasks.lock().await.take();
+
+        if let Some((acceptor, threads)) = tasks {
+            if acceptor.await.is_err() {
+                error!("Server task panicked.");
+            }
+
+            let joined = tokio::task::spawn_blocking(move || {
+                threads.into_iter().all(|thread| thread.join().is_ok())
+            }).await;
+
+            if !matches!(joined, Ok(true)) {
+                error!("Server thread panicked.");
+            }
+        }
+
+        // Every connection holds on to a slot until it's done
+        let slots = AsyncServerState::slots(self.state.config());
+        self.state.slots.acquire_many(slots).await.ok();
+    }
+}
+
+impl Drop for AsyncServerHandle {
+    fn drop(&mut self) {
+        if let Some(addr) = self.stop() {
+            ServerHandle::wake(addr);
+        }
+    }
+}
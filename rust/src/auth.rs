@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+/// Buckets kept before the least recently used ones are dropped.
+const MAX_BUCKETS: usize = 16384;
+
+/// Share of buckets dropped at once, so that doesn't happen on every check.
+const EVICTED_BUCKETS: usize = MAX_BUCKETS / 4;
+
+/**
+ * Decides which clients a server answers. Given the client's address and
+ * the token it sent, returns the identity its queries count against, or
+ * None to turn it away.
+ *
+ * Tokens are sent as is, so they should only be used over TLS.
+ * `Authorizer::tokens` looks them up by their SHA-256 hash, so how long that
+ * takes doesn't give away how much of a guess was right.
+ *
+ * ```
+ * use std::collections::HashMap;
+ * use hybridpir::auth::Authorizer;
+ *
+ * let mut tokens = HashMap::new();
+ * tokens.insert(b"secret".to_vec(), "alice".to_string());
+ *
+ * let authorizer = Authorizer::tokens(tokens);
+ * let peer = "127.0.0.1:1234".parse().unwrap();
+ *
+ * assert!(authorizer.authorize(peer, b"secret") == Some("alice".to_string()));
+ * assert!(authorizer.authorize(peer, b"guess") == None);
+ * ```
+ */
+#[derive(Clone)]
+pub struct Authorizer(Arc<dyn Fn(SocketAddr, &[u8]) -> Option<String> + Send + Sync>);
+
+impl Authorizer {
+    pub fn new<F>(f: F) -> Self
+        where F: Fn(SocketAddr, &[u8]) -> Option<String> + Send + Sync + 'static
+    {
+        Authorizer(Arc::new(f))
+    }
+
+    /**
+     * Accept a fixed set of bearer tokens, each belonging to the given
+     * identity.
+     */
+    pub fn tokens(tokens: HashMap<Vec<u8>, String>) -> Self {
+        let tokens: HashMap<Vec<u8>, String> = tokens
+            .into_iter()
+            .map(|(token, client)| (Sha256::digest(&token).to_vec(), client))
+            .collect();
+
+        Self::new(move |_, token| tokens.get(&Sha256::digest(token)[..]).cloned())
+    }
+
+    pub fn authorize(&self, peer: SocketAddr, token: &[u8]) -> Option<String> {
+        (self.0)(peer, token)
+    }
+}
+
+impl fmt::Debug for Authorizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Authorizer")
+    }
+}
+
+/**
+ * Allow up to `queries` queries per `period`, in bursts of up to `queries`.
+ * Batch queries count once for every RaidPIR chunk they touch, since each of
+ * those costs the server a pass over the database.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub queries: u32,
+    pub period: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Client(String),
+    Ip(IpAddr),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/**
+ * Token buckets for every client and address seen recently.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+impl RateLimiter {
+    /**
+     * Charge `cost` queries to the given address and, if known, client
+     * identity, returning false without charging either if one of them is
+     * over its limit.
+     */
+    pub(crate) fn check(&self,
+        ip: IpAddr,
+        ip_limit: Option<RateLimit>,
+        client: Option<&str>,
+        client_limit: Option<RateLimit>,
+        cost: usize
+    ) -> bool {
+        let mut keys: Vec<(Key, RateLimit)> = Vec::with_capacity(2);
+
+        if let Some(limit) = ip_limit {
+            keys.push((Key::Ip(Self::network(ip)), limit));
+        }
+
+        if let (Some(client), Some(limit)) = (client, client_limit) {
+            keys.push((Key::Client(client.to_string()), limit));
+        }
+
+        if keys.is_empty() {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_BUCKETS {
+            Self::evict(&mut buckets);
+        }
+
+        let allowed = keys.iter().all(|(key, limit)| {
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: limit.queries as f64,
+                updated: now,
+            });
+
+            Self::refill(bucket, *limit, now) >= cost as f64
+        });
+
+        if allowed {
+            for (key, _) in keys.iter() {
+                buckets.get_mut(key).unwrap().tokens -= cost as f64;
+            }
+        }
+
+        allowed
+    }
+
+    /**
+     * Address that counts against the per-IP limit. IPv6 clients usually get
+     * a whole /64 to pick addresses from, so that's counted as one.
+     */
+    fn network(ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(_) => ip,
+            IpAddr::V6(ip) => match ip.octets() {
+                // IPv4-mapped
+                [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+                _ => {
+                    let [a, b, c, d, ..] = ip.segments();
+                    IpAddr::V6(Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0))
+                },
+            },
+        }
+    }
+
+    /**
+     * Whether the given address has a query left, without charging it.
+     */
+    pub(crate) fn has_quota(&self, ip: IpAddr, ip_limit: Option<RateLimit>) -> bool {
+        let limit = match ip_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        match buckets.get_mut(&Key::Ip(Self::network(ip))) {
+            Some(bucket) => Self::refill(bucket, limit, Instant::now()) >= 1.0,
+            None => true,
+        }
+    }
+
+    /**
+     * Drop the buckets that were used least recently.
+     */
+    fn evict(buckets: &mut HashMap<Key, Bucket>) {
+        let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+        let cutoff = *updated.select_nth_unstable(EVICTED_BUCKETS).1;
+
+        buckets.retain(|_, bucket| bucket.updated > cutoff);
+    }
+
+    fn refill(bucket: &mut Bucket, limit: RateLimit, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        let rate = limit.queries as f64 / limit.period.as_secs_f64();
+
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(limit.queries as f64);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
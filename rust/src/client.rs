@@ -1,5 +1,8 @@
+use std::collections::HashMap;
+#[cfg(feature = "tls")]
+use std::convert::TryFrom;
 use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
-use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use bitvec::prelude::*;
@@ -9,60 +12,308 @@ use sealpir::client::PirClient;
 use sealpir::{PirQuery, PirReply};
 use rayon::prelude::*;
 
+use crate::error::HybridPirError;
+use crate::keyword::KeywordLayout;
+use crate::params::{HybridPirParams, ParamsFingerprint};
+use crate::server::MAX_BATCH_SIZE;
+use crate::stream::Stream;
+use crate::streaming::ReplyAssembler;
 use crate::types::*;
 
+/**
+ * How much of a response streamed in parts has arrived from one server, in
+ * serialized bytes.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub peer: SocketAddr,
+    pub received: u64,
+    pub total: u64,
+}
+
+/**
+ * Shape every batch query is padded to, see
+ * `HybridPirClient::set_batch_padding`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchPadding {
+    pub chunks: usize,
+    pub queries_per_chunk: usize,
+}
+
 pub struct HybridPirClient<'a> {
-    db_len: usize,
+    params: HybridPirParams,
+    fingerprint: ParamsFingerprint,
     raidpir: RaidPirClient,
-    raidpir_servers: usize,
     raidpir_chunksize: usize,
     sealpir: PirClient<'a>,
+    session: u64,
+    connections: Mutex<HashMap<SocketAddr, Stream>>,
+    #[cfg(feature = "tls")]
+    tls: HashMap<SocketAddr, (rustls::ServerName, Arc<rustls::ClientConfig>)>,
+    tokens: HashMap<SocketAddr, Vec<u8>>,
+    size_limits: SizeLimits,
+    compression: Vec<Compression>,
+    encoding: Encoding,
+    progress: Option<Arc<dyn Fn(Progress) + Send + Sync>>,
+    batch_padding: Option<BatchPadding>,
 }
 
 impl HybridPirClient<'_> {
-    pub fn new(
-        db_len: usize,
-        element_size: usize,
-        raidpir_servers: usize,
-        raidpir_redundancy: usize,
-        raidpir_size: usize,
-        sealpir_poly_degree: u32,
-        sealpir_log: u32,
-        sealpir_d: u32,
-    ) -> Self {
-        assert!(raidpir_size < db_len);
-        assert!(raidpir_size % (raidpir_servers * 8) == 0);
+    pub fn new(params: &HybridPirParams) -> Result<Self, HybridPirError> {
+        params.validate()?;
 
         let raidpir = RaidPirClient::new(
-            raidpir_size,
-            raidpir_servers,
-            raidpir_redundancy);
+            params.raidpir_size,
+            params.raidpir_servers,
+            params.raidpir_redundancy);
 
-        let raidpir_chunksize = (db_len as f32 / raidpir_size as f32).ceil() as usize;
+        let raidpir_chunksize = params.raidpir_chunksize();
 
         let sealpir = PirClient::new(
             raidpir_chunksize as u32,
-            element_size as u32,
-            sealpir_poly_degree as u32,
-            sealpir_log as u32,
-            sealpir_d as u32);
+            params.element_size as u32,
+            params.sealpir_poly_degree,
+            params.sealpir_log,
+            params.sealpir_d);
 
-        Self {
-            db_len,
+        let session = session_id(sealpir.get_key());
+
+        Ok(Self {
+            params: params.clone(),
+            fingerprint: params.fingerprint(),
             raidpir,
-            raidpir_servers,
             raidpir_chunksize,
             sealpir,
+            session,
+            connections: Mutex::new(HashMap::new()),
+            #[cfg(feature = "tls")]
+            tls: HashMap::new(),
+            tokens: HashMap::new(),
+            size_limits: SizeLimits::default(),
+            compression: Vec::new(),
+            encoding: Encoding::Bincode,
+            progress: None,
+            batch_padding: None,
+        })
+    }
+
+    /**
+     * Connect to the given server over TLS, expecting a certificate for
+     * `server_name` signed by one of the roots in `config`. Servers without
+     * TLS settings are connected to over plain TCP. `AsyncHybridPirClient`
+     * doesn't support TLS, and refuses to connect to these servers at all.
+     */
+    #[cfg(feature = "tls")]
+    pub fn set_tls<A: ToSocketAddrs>(&mut self,
+        target: A,
+        server_name: &str,
+        config: Arc<rustls::ClientConfig>
+    ) -> Result<(), HybridPirError> {
+        let name = rustls::ServerName::try_from(server_name)
+            .map_err(|_| HybridPirError::InvalidParameters(format!("Invalid server name {}.", server_name)))?;
+
+        let addresses: Vec<SocketAddr> = target
+            .to_socket_addrs()
+            .map_err(|e| HybridPirError::Resolution(format!("{}", e)))?
+            .collect();
+
+        for address in addresses {
+            self.tls.insert(address, (name.clone(), config.clone()));
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Present `token` to the given server if it asks us to authenticate.
+     * Tokens are sent as is, so this should go along with `set_tls`.
+     */
+    pub fn set_token<A: ToSocketAddrs>(&mut self, target: A, token: Vec<u8>) -> Result<(), HybridPirError> {
+        let addresses: Vec<SocketAddr> = target
+            .to_socket_addrs()
+            .map_err(|e| HybridPirError::Resolution(format!("{}", e)))?
+            .collect();
+
+        for address in addresses {
+            self.tokens.insert(address, token.clone());
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Whether the given server is to be connected to over TLS.
+     */
+    #[cfg(feature = "async")]
+    pub(crate) fn uses_tls(&self, target: &SocketAddr) -> bool {
+        #[cfg(feature = "tls")]
+        {
+            self.tls.contains_key(target)
+        }
+
+        #[cfg(not(feature = "tls"))]
+        {
+            let _ = target;
+            false
         }
     }
 
+    /**
+     * Token to present to the given server, if it asks for one.
+     */
+    pub(crate) fn token(&self, target: &SocketAddr) -> Result<&Vec<u8>, HybridPirError> {
+        self.tokens.get(target).ok_or(HybridPirError::Unauthorized { peer: None })
+    }
+
+    /**
+     * Refuse messages from servers larger than `limits` allows for their
+     * type. The defaults may be too small for very large batch queries.
+     */
+    pub fn set_size_limits(&mut self, limits: SizeLimits) {
+        self.size_limits = limits;
+    }
+
+    pub fn size_limits(&self) -> &SizeLimits {
+        &self.size_limits
+    }
+
+    /**
+     * Ask servers to compress large messages with one of the given methods,
+     * best first. Off by default, since compression costs both sides CPU
+     * time that's only worth it on slow connections.
+     */
+    pub fn set_compression(&mut self, preferences: Vec<Compression>) -> Result<(), HybridPirError> {
+        if let Some(c) = preferences.iter().find(|c| !c.is_supported()) {
+            return Err(HybridPirError::InvalidParameters(format!("Compression {:?} not supported.", c)));
+        }
+
+        self.compression = preferences;
+        Ok(())
+    }
+
+    pub fn compression(&self) -> &[Compression] {
+        &self.compression
+    }
+
+    /**
+     * Talk to servers in the given encoding, starting with the next query
+     * cycle. Servers always answer in the encoding they were asked in.
+     */
+    pub fn set_encoding(&mut self, encoding: Encoding) -> Result<(), HybridPirError> {
+        if !encoding.is_supported() {
+            return Err(HybridPirError::InvalidParameters(format!("Encoding {:?} not supported.", encoding)));
+        }
+
+        self.encoding = encoding;
+        Ok(())
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /**
+     * Call `f` whenever another part of a streamed response arrives. Servers
+     * only stream responses too large for a single message, see
+     * `ServerConfig::response_part_size`. Parts of different servers arrive
+     * in parallel, so `f` may be called from several threads at once.
+     */
+    pub fn set_progress<F>(&mut self, f: F)
+        where F: Fn(Progress) + Send + Sync + 'static
+    {
+        self.progress = Some(Arc::new(f));
+    }
+
+    pub(crate) fn report_progress(&self, progress: Progress) {
+        if let Some(f) = self.progress.as_ref() {
+            f(progress);
+        }
+    }
+
+    /**
+     * Pad every batch query to the same number of RaidPIR chunks, each with
+     * the same number of SealPIR queries, so servers can't tell how the
+     * requested indices group into chunks. Padding is made up of queries for
+     * elements nobody asked for, whose replies are thrown away.
+     *
+     * Batches that don't fit are refused, so this has to allow for the
+     * largest batch ever sent: `chunks` as many as indices, and
+     * `queries_per_chunk` as many as may end up in the same chunk.
+     */
+    pub fn set_batch_padding(&mut self, padding: Option<BatchPadding>) -> Result<(), HybridPirError> {
+        if let Some(padding) = padding {
+            let chunks = (self.params.db_len + self.raidpir_chunksize - 1) / self.raidpir_chunksize;
+
+            if padding.chunks == 0 || padding.chunks > chunks.min(MAX_BATCH_SIZE) {
+                return Err(HybridPirError::InvalidParameters(
+                    format!("Batches must be padded to between 1 and {} chunks.", chunks.min(MAX_BATCH_SIZE))));
+            }
+
+            if padding.queries_per_chunk == 0 || padding.queries_per_chunk > self.raidpir_chunksize {
+                return Err(HybridPirError::InvalidParameters(
+                    format!("Batches must be padded to between 1 and {} queries per chunk.", self.raidpir_chunksize)));
+            }
+        }
+
+        self.batch_padding = padding;
+        Ok(())
+    }
+
+    pub fn batch_padding(&self) -> Option<BatchPadding> {
+        self.batch_padding
+    }
+
+    pub fn params(&self) -> &HybridPirParams {
+        &self.params
+    }
+
+    pub fn servers(&self) -> usize {
+        self.params.raidpir_servers
+    }
+
     pub fn sealpir_key(&self) -> &Vec<u8> {
         self.sealpir.get_key()
     }
 
-    pub fn query(&self, index: usize, seeds: &Vec<u128>) -> (Vec<BitVec<Lsb0, u8>>, PirQuery) {
-        assert!(index < self.db_len);
-        assert!(seeds.len() == self.raidpir_servers);
+    /**
+     * ID under which servers cache this client's SealPIR key, sent with every
+     * hello message.
+     */
+    pub fn session_id(&self) -> u64 {
+        self.session
+    }
+
+    /**
+     * Fingerprint of our parameters, sent with every hello message.
+     */
+    pub fn fingerprint(&self) -> ParamsFingerprint {
+        self.fingerprint
+    }
+
+    fn check_index(&self, index: usize) -> Result<(), HybridPirError> {
+        if index >= self.params.db_len {
+            return Err(HybridPirError::IndexOutOfRange {
+                index,
+                db_len: self.params.db_len,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn check_servers(&self, what: &str, n: usize) -> Result<(), HybridPirError> {
+        if n != self.params.raidpir_servers {
+            return Err(HybridPirError::InvalidParameters(
+                format!("Expected {} {}, got {}.", self.params.raidpir_servers, what, n)));
+        }
+
+        Ok(())
+    }
+
+    pub fn query(&self, index: usize, seeds: &Vec<u128>) -> Result<(Vec<BitVec<Lsb0, u8>>, PirQuery), HybridPirError> {
+        self.check_index(index)?;
+        self.check_servers("seeds", seeds.len())?;
 
         let raidpir_index = index / self.raidpir_chunksize;
         let raidpir_queries = self.raidpir.query(raidpir_index, seeds);
@@ -70,10 +321,13 @@ impl HybridPirClient<'_> {
         let sealpir_index = index - raidpir_index * self.raidpir_chunksize;
         let sealpir_query = self.sealpir.gen_query(sealpir_index as u32);
 
-        (raidpir_queries, sealpir_query)
+        Ok((raidpir_queries, sealpir_query))
     }
 
-    pub fn combine(&self, index: usize, responses: Vec<PirReply>) -> Vec<u8> {
+    pub fn combine(&self, index: usize, responses: Vec<PirReply>) -> Result<Vec<u8>, HybridPirError> {
+        self.check_index(index)?;
+        self.check_servers("responses", responses.len())?;
+
         let raidpir_index = index / self.raidpir_chunksize;
         let sealpir_index = index - raidpir_index * self.raidpir_chunksize;
 
@@ -82,88 +336,683 @@ impl HybridPirClient<'_> {
             .map(|response| self.sealpir.decode_reply(sealpir_index as u32, &response))
             .collect();
 
+        self.combine_decoded(sealpir_responses)
+    }
+
+    /**
+     * Combine every server's SealPIR reply, already decoded, into the
+     * element.
+     */
+    fn combine_decoded(&self, sealpir_responses: Vec<Vec<u8>>) -> Result<Vec<u8>, HybridPirError> {
         let raidpir_response = self.raidpir
             .combine(sealpir_responses.into_iter().map(|r| RaidPirData::new(r)).collect());
 
-        raidpir_response.into()
+        self.params.decode_record(raidpir_response.into())
+    }
+
+    /**
+     * Group indices by RaidPIR chunk. Returns every chunk touched along with
+     * the distinct SealPIR indices queried within it, in order of first
+     * appearance, followed by any padding.
+     */
+    fn batch_layout(&self, indices: &[usize]) -> Vec<(usize, Vec<usize>)> {
+        let mut layout = self.unpadded_batch_layout(indices);
+
+        let padding = match self.batch_padding {
+            Some(padding) => padding,
+            None => return layout,
+        };
+
+        // Servers can't tell which chunks and elements are queried anyway, so
+        // any that aren't used yet will do.
+        let unused: Vec<usize> = (0..)
+            .filter(|chunk| !layout.iter().any(|(c, _)| c == chunk))
+            .take(padding.chunks.saturating_sub(layout.len()))
+            .collect();
+
+        layout.extend(unused.into_iter().map(|chunk| (chunk, vec![])));
+
+        for (_, sealpir_indices) in layout.iter_mut() {
+            let mut dummy = 0;
+            while sealpir_indices.len() < padding.queries_per_chunk {
+                if !sealpir_indices.contains(&dummy) {
+                    sealpir_indices.push(dummy);
+                }
+                dummy += 1;
+            }
+        }
+
+        layout
     }
 
-    pub fn send_query<A: ToSocketAddrs>(&self, targets: &[A], index: usize) -> Result<Vec<u8>, Error> {
-        let addresses: Vec<SocketAddr> = targets
+    fn unpadded_batch_layout(&self, indices: &[usize]) -> Vec<(usize, Vec<usize>)> {
+        let mut layout: Vec<(usize, Vec<usize>)> = Vec::new();
+
+        for index in indices {
+            let raidpir_index = index / self.raidpir_chunksize;
+            let sealpir_index = index - raidpir_index * self.raidpir_chunksize;
+
+            match layout.iter_mut().find(|(chunk, _)| *chunk == raidpir_index) {
+                Some((_, sealpir_indices)) => {
+                    if !sealpir_indices.contains(&sealpir_index) {
+                        sealpir_indices.push(sealpir_index);
+                    }
+                },
+                None => layout.push((raidpir_index, vec![sealpir_index])),
+            }
+        }
+
+        layout
+    }
+
+    /**
+     * Make sure a batch fits the padding, if any.
+     */
+    pub(crate) fn check_batch(&self, indices: &[usize]) -> Result<(), HybridPirError> {
+        let padding = match self.batch_padding {
+            Some(padding) => padding,
+            None => return Ok(()),
+        };
+
+        let layout = self.unpadded_batch_layout(indices);
+        if layout.len() > padding.chunks || layout.iter().any(|(_, s)| s.len() > padding.queries_per_chunk) {
+            return Err(HybridPirError::InvalidParameters(format!(
+                "Batch doesn't fit padding of {} chunks with {} queries each.",
+                padding.chunks, padding.queries_per_chunk)));
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Number of seeds every server has to provide for a batch query of the
+     * given indices, i.e. the number of distinct RaidPIR chunks involved,
+     * or the number of chunks batches are padded to.
+     */
+    pub fn batch_seeds(&self, indices: &[usize]) -> usize {
+        self.batch_layout(indices).len()
+    }
+
+    /**
+     * Number of SealPIR replies per chunk in the response to a batch query.
+     */
+    pub(crate) fn batch_shape(&self, indices: &[usize]) -> Vec<usize> {
+        self.batch_layout(indices)
             .iter()
-            .map(|x| x.to_socket_addrs().unwrap().next().unwrap())
+            .map(|(_, sealpir_indices)| sealpir_indices.len())
+            .collect()
+    }
+
+    /**
+     * Generate queries for several indices at once. `seeds` contains the
+     * seeds of every server, `batch_seeds(indices)` each.
+     *
+     * Indices in the same RaidPIR chunk share a RaidPIR query. Returns the
+     * RaidPIR queries per server and chunk, and the SealPIR queries per chunk.
+     */
+    pub fn query_batch(&self,
+        indices: &[usize],
+        seeds: &Vec<Vec<u128>>
+    ) -> Result<(Vec<Vec<BitVec<Lsb0, u8>>>, Vec<Vec<PirQuery>>), HybridPirError> {
+        for index in indices {
+            self.check_index(*index)?;
+        }
+        self.check_batch(indices)?;
+        self.check_servers("sets of seeds", seeds.len())?;
+
+        let layout = self.batch_layout(indices);
+        if let Some(s) = seeds.iter().find(|s| s.len() != layout.len()) {
+            return Err(HybridPirError::InvalidParameters(
+                format!("Expected {} seeds per server, got {}.", layout.len(), s.len())));
+        }
+
+        let mut raidpir_queries: Vec<Vec<BitVec<Lsb0, u8>>> =
+            vec![Vec::with_capacity(layout.len()); self.params.raidpir_servers];
+        let mut sealpir_queries: Vec<Vec<PirQuery>> = Vec::with_capacity(layout.len());
+
+        for (i, (raidpir_index, sealpir_indices)) in layout.iter().enumerate() {
+            let chunk_seeds: Vec<u128> = seeds.iter().map(|s| s[i]).collect();
+
+            let queries = self.raidpir.query(*raidpir_index, &chunk_seeds);
+            for (server, query) in queries.into_iter().enumerate() {
+                raidpir_queries[server].push(query);
+            }
+
+            sealpir_queries.push(sealpir_indices
+                .iter()
+                .map(|sealpir_index| self.sealpir.gen_query(*sealpir_index as u32))
+                .collect());
+        }
+
+        Ok((raidpir_queries, sealpir_queries))
+    }
+
+    /**
+     * Decode the responses to a batch query, given per server, chunk and
+     * SealPIR query. Elements are returned in the order of `indices`.
+     */
+    pub fn combine_batch(&self, indices: &[usize], responses: Vec<Vec<Vec<PirReply>>>) -> Result<Vec<Vec<u8>>, HybridPirError> {
+        self.check_servers("sets of responses", responses.len())?;
+
+        let layout = self.batch_layout(indices);
+        if !responses.iter().all(|r| self.batch_shape_matches(&layout, r)) {
+            return Err(HybridPirError::InvalidParameters(
+                "Responses don't match the batch query.".into()));
+        }
+
+        let sealpir_indices = self.flat_sealpir_indices(indices, &layout);
+
+        let sealpir_responses: Vec<Vec<Vec<u8>>> = responses
+            .into_iter()
+            .map(|response| {
+                let replies: Vec<PirReply> = response.into_iter().flatten().collect();
+
+                replies
+                    .par_iter()
+                    .zip(sealpir_indices.par_iter())
+                    .map(|(reply, sealpir_index)| self.decode_batch_reply(*sealpir_index, reply))
+                    .collect()
+            })
             .collect();
-        assert!(addresses.len() == self.raidpir_servers);
-
-        // Init connections
-        let mut streams: Vec<TcpStream> = addresses
-            .par_iter() // Establish connections in parallel
-            .map(|target| {
-                let stream = TcpStream::connect(target)?;
-                stream.set_read_timeout(Some(Duration::from_secs(60)))?;
-                stream.set_write_timeout(Some(Duration::from_secs(60)))?;
-                stream.set_nodelay(true)?;
-                Ok(stream)
+
+        self.combine_batch_decoded(indices, &layout, sealpir_responses)
+    }
+
+    /**
+     * SealPIR index of every reply in the response to a batch query, in the
+     * order they are sent, or None for padding.
+     */
+    fn flat_sealpir_indices(&self, indices: &[usize], layout: &[(usize, Vec<usize>)]) -> Vec<Option<u32>> {
+        layout
+            .iter()
+            .flat_map(|(raidpir_index, sealpir_indices)| sealpir_indices
+                .iter()
+                .map(move |i| (raidpir_index * self.raidpir_chunksize + i, *i as u32)))
+            .map(|(index, sealpir_index)| if indices.contains(&index) { Some(sealpir_index) } else { None })
+            .collect()
+    }
+
+    /**
+     * Decode a SealPIR reply to a batch query, skipping padding.
+     */
+    fn decode_batch_reply(&self, sealpir_index: Option<u32>, reply: &PirReply) -> Vec<u8> {
+        match sealpir_index {
+            Some(sealpir_index) => self.sealpir.decode_reply(sealpir_index, reply),
+            None => Vec::new(),
+        }
+    }
+
+    /**
+     * Combine the decoded SealPIR replies to a batch query, given per server
+     * in the order they are sent.
+     */
+    fn combine_batch_decoded(&self,
+        indices: &[usize],
+        layout: &[(usize, Vec<usize>)],
+        sealpir_responses: Vec<Vec<Vec<u8>>>
+    ) -> Result<Vec<Vec<u8>>, HybridPirError> {
+        let mut elements: HashMap<usize, Vec<u8>> = HashMap::with_capacity(indices.len());
+        let mut k = 0;
+
+        for (raidpir_index, sealpir_indices) in layout.iter() {
+            for sealpir_index in sealpir_indices.iter() {
+                let index = raidpir_index * self.raidpir_chunksize + sealpir_index;
+
+                // Padding is never asked for
+                if indices.contains(&index) {
+                    let replies: Vec<Vec<u8>> = sealpir_responses
+                        .iter()
+                        .map(|r| r[k].clone())
+                        .collect();
+
+                    elements.insert(index, self.combine_decoded(replies)?);
+                }
+
+                k += 1;
+            }
+        }
+
+        Ok(indices.iter().map(|index| elements[index].clone()).collect())
+    }
+
+    /**
+     * Whether a server's response to a batch query contains a reply for
+     * every SealPIR query sent.
+     */
+    fn batch_shape_matches(&self, layout: &[(usize, Vec<usize>)], response: &[Vec<PirReply>]) -> bool {
+        response.len() == layout.len() && response
+            .iter()
+            .zip(layout.iter())
+            .all(|(replies, (_, sealpir_indices))| replies.len() == sealpir_indices.len())
+    }
+
+    fn connect(&self, target: &SocketAddr) -> Result<Stream, HybridPirError> {
+        let stream = TcpStream::connect(target)?;
+        stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(60)))?;
+        stream.set_nodelay(true)?;
+
+        #[cfg(feature = "tls")]
+        {
+            if let Some((name, config)) = self.tls.get(target) {
+                let connection = rustls::ClientConnection::new(config.clone(), name.clone())
+                    .map_err(|e| HybridPirError::InvalidParameters(format!("{}", e)))?;
+
+                return Ok(Stream::tls_client(connection, stream));
+            }
+        }
+
+        Ok(Stream::plain(stream))
+    }
+
+    /**
+     * Read the server's next message, turning error replies into errors.
+     */
+    fn receive(&self, stream: &mut Stream) -> Result<HybridPirMessage, HybridPirError> {
+        stream.receive(&self.size_limits)?.into_result()
+    }
+
+    /**
+     * Get ready to put together a streamed response, announced with the
+     * given reply sizes. `shape` is the number of replies per chunk for
+     * batch queries, and None for single ones.
+     */
+    pub(crate) fn reply_assembler(&self,
+        sizes: Vec<u32>,
+        shape: Option<&[usize]>,
+        encoding: Encoding
+    ) -> Result<ReplyAssembler, HybridPirError> {
+        let (count, limit) = match shape {
+            None => (1, self.size_limits.get(MessageType::Response)),
+            Some(shape) => (shape.iter().sum(), self.size_limits.get(MessageType::BatchResponse)),
+        };
+
+        ReplyAssembler::new(sizes, count, limit, encoding)
+    }
+
+    /**
+     * Receive the SealPIR replies to a query, decoding each with `decode` as
+     * soon as it's complete. For streamed batch responses, that's while later
+     * replies are still on their way, a single reply can only be decoded
+     * once all its parts are in. `shape` is as for `reply_assembler`,
+     * replies are numbered in the order they are sent.
+     */
+    fn receive_replies<F>(&self,
+        stream: &mut Stream,
+        shape: Option<&[usize]>,
+        decode: F
+    ) -> Result<Vec<Vec<u8>>, HybridPirError>
+        where F: Fn(usize, &PirReply) -> Vec<u8> + Sync
+    {
+        let sizes = match (self.receive(stream)?, shape) {
+            (HybridPirMessage::Response(reply), None) => return Ok(vec![decode(0, &reply)]),
+            (HybridPirMessage::BatchResponse(replies), Some(shape))
+                if replies.len() == shape.len() && replies.iter().zip(shape).all(|(r, n)| r.len() == *n) =>
+            {
+                let replies: Vec<PirReply> = replies.into_iter().flatten().collect();
+
+                return Ok(replies
+                    .par_iter()
+                    .enumerate()
+                    .map(|(i, reply)| decode(i, reply))
+                    .collect());
+            },
+            (HybridPirMessage::ResponseStart(sizes), _) => sizes,
+            (m, _) => return Err(HybridPirError::unexpected(&m)),
+        };
+
+        let mut assembler = self.reply_assembler(sizes, shape, stream.encoding)?;
+        let decoded = Mutex::new(vec![Vec::new(); shape.map_or(1, |s| s.iter().sum())]);
+        let peer = stream.peer_addr()?;
+
+        rayon::scope(|scope| {
+            while !assembler.is_done() {
+                let part = match self.receive(stream)? {
+                    HybridPirMessage::ResponsePart(part) => part,
+                    m => return Err(HybridPirError::unexpected(&m)),
+                };
+
+                if let Some((i, reply)) = assembler.push(part)? {
+                    let (decode, decoded) = (&decode, &decoded);
+
+                    scope.spawn(move |_| {
+                        let reply = decode(i, &reply);
+                        decoded.lock().unwrap()[i] = reply;
+                    });
+                }
+
+                self.report_progress(Progress {
+                    peer,
+                    received: assembler.received(),
+                    total: assembler.total(),
+                });
+            }
+
+            Ok(())
+        })?;
+
+        Ok(decoded.into_inner().unwrap())
+    }
+
+    /**
+     * Start a new query cycle on the given connection and return the server's
+     * answer along with the compression agreed on, authenticating and
+     * registering our SealPIR key first if the server asks for it.
+     */
+    fn hello(&self,
+        mut stream: &mut Stream,
+        hello: &HybridPirMessage
+    ) -> Result<(HybridPirMessage, Compression), HybridPirError> {
+        stream.encoding = self.encoding;
+        stream.send(hello, Compression::None)?;
+
+        let mut response = self.receive(&mut stream)?;
+
+        if let HybridPirMessage::ParamsMismatch(remote) = response {
+            return Err(HybridPirError::ParamsMismatch {
+                peer: None,
+                local: self.fingerprint,
+                remote,
+            });
+        }
+
+        // Only sent if we asked for compression
+        let mut compression = Compression::None;
+        if let HybridPirMessage::UseCompression(c) = response {
+            if !self.compression.contains(&c) && c != Compression::None {
+                return Err(HybridPirError::protocol(format!("Server chose compression {:?}, which we didn't offer.", c)));
+            }
+
+            compression = c;
+            response = self.receive(&mut stream)?;
+        }
+
+        // Only asked once per connection, before anything else
+        if response == HybridPirMessage::AuthRequired {
+            let message = HybridPirMessage::Authenticate(self.token(&stream.peer_addr()?)?.clone());
+            stream.send(&message, Compression::None)?;
+
+            response = self.receive(&mut stream)?;
+        }
+
+        // Server doesn't know our key yet, upload it once
+        if response == HybridPirMessage::KeyRequired {
+            debug!("[{:?}] Registering key...", stream.peer_addr()?);
+
+            let message = HybridPirMessage::RegisterKey(self.sealpir_key().clone());
+            stream.send(&message, compression)?;
+
+            response = self.receive(&mut stream)?;
+        }
+
+        Ok((response, compression))
+    }
+
+    /**
+     * Send the hello message to every server, reusing connections left open
+     * by previous queries where possible. Returns the connections, and each
+     * server's answer and compression.
+     */
+    fn start_cycle(&self,
+        addresses: &[SocketAddr],
+        hello: &HybridPirMessage
+    ) -> Result<(Vec<Stream>, Vec<(HybridPirMessage, Compression)>), HybridPirError> {
+        let pooled: Vec<Option<Stream>> = {
+            let mut connections = self.connections.lock().unwrap();
+            addresses.iter().map(|a| connections.remove(a)).collect()
+        };
+
+        let (streams, responses) = addresses
+            .par_iter()
+            .zip(pooled.into_par_iter())
+            .map(|(target, stream)| {
+                // The server may have closed an idle connection in the
+                // meantime, in that case just start over on a new one. Any
+                // other error is the server's answer, and asking again on a
+                // new connection won't change it.
+                if let Some(mut stream) = stream {
+                    match self.hello(&mut stream, hello) {
+                        Ok(response) => return Ok((stream, response)),
+                        Err(e) if e.is_disconnect() => debug!("[{:?}] Reconnecting ({})...", target, e),
+                        Err(e) => return Err(e.with_peer(*target)),
+                    }
+                }
+
+                let mut stream = self.connect(target)
+                    .map_err(|e| e.with_peer(*target))?;
+                let response = self.hello(&mut stream, hello)
+                    .map_err(|e| e.with_peer(*target))?;
+                Ok((stream, response))
             })
             .with_max_len(1) // Ensure each iteration gets a thread
-            .collect::<Result<Vec<TcpStream>, Error>>()?;
+            .collect::<Result<Vec<(Stream, (HybridPirMessage, Compression))>, HybridPirError>>()?
+            .into_iter()
+            .unzip();
+
+        Ok((streams, responses))
+    }
+
+    /**
+     * Keep connections open for the next query.
+     */
+    fn end_cycle(&self, addresses: Vec<SocketAddr>, streams: Vec<Stream>) {
+        self.connections.lock().unwrap().extend(addresses.into_iter().zip(streams));
+    }
+
+    fn resolve<A: ToSocketAddrs>(&self, targets: &[A]) -> Result<Vec<SocketAddr>, HybridPirError> {
+        self.check_servers("servers", targets.len())?;
+
+        targets
+            .iter()
+            .enumerate()
+            .map(|(i, target)| {
+                target
+                    .to_socket_addrs()
+                    .map_err(|e| HybridPirError::Resolution(format!("server {}: {}", i, e)))?
+                    .next()
+                    .ok_or(HybridPirError::Resolution(format!("server {}: no addresses found", i)))
+            })
+            .collect()
+    }
+
+    pub fn send_query<A: ToSocketAddrs>(&self, targets: &[A], index: usize) -> Result<Vec<u8>, HybridPirError> {
+        self.check_index(index)?;
+
+        let addresses = self.resolve(targets)?;
 
         // Send hello message and retrieve seed for each server
-        let seeds: Vec<u128> = streams
-            .par_iter_mut()
-            .map(|mut stream| {
-                let hello = HybridPirMessage::Hello;
-                hello.write_to(&mut stream)?;
-
-                match HybridPirMessage::read_from(&mut stream)? {
-                    HybridPirMessage::Seed(s) => {
-                        debug!("[{:?}] Received seed: {:?}.",
-                            stream.peer_addr().unwrap(), s);
-                        Ok(s)
-                    },
-                    _ => Err(Error::new(ErrorKind::Other, "Unexpected Response."))
-                }
+        let hello = HybridPirMessage::Hello(self.session, self.fingerprint, self.compression.clone());
+        let (mut streams, responses) = self.start_cycle(&addresses, &hello)?;
+        let (responses, compression): (Vec<HybridPirMessage>, Vec<Compression>) = responses.into_iter().unzip();
+
+        let seeds: Vec<u128> = addresses
+            .iter()
+            .zip(responses.into_iter())
+            .map(|(target, response)| match response {
+                HybridPirMessage::Seed(s) => {
+                    debug!("[{:?}] Received seed: {:?}.", target, s);
+                    Ok(s)
+                },
+                m => Err(HybridPirError::unexpected(&m).with_peer(*target))
             })
-            .with_max_len(1)
-            .collect::<Result<Vec<u128>, Error>>()?;
+            .collect::<Result<Vec<u128>, HybridPirError>>()?;
 
         let t1 = Instant::now();
 
         debug!("Received all seeds, calculating query...");
 
-        let (raidpir_queries, sealpir_query) = self.query(index, &seeds);
+        let (raidpir_queries, sealpir_query) = self.query(index, &seeds)?;
 
         debug!("Calculated query ({:.4}ms).",
             t1.elapsed().as_secs_f64() * 1000.0);
 
-        // Send queries and retrieve responses
-        let responses: Vec<PirReply> = streams
+        let sealpir_index = (index % self.raidpir_chunksize) as u32;
+
+        // Send queries and retrieve responses, decoding them right away
+        let responses: Vec<Vec<u8>> = streams
             .par_iter_mut()
-            .zip(raidpir_queries.par_iter())
-            .map(|(mut stream, raidpir_query)| {
+            .zip(addresses.par_iter().zip(raidpir_queries.par_iter()).zip(compression.par_iter()))
+            .map(|(mut stream, ((target, raidpir_query), compression))| {
                 let t2 = Instant::now();
 
-                debug!("[{:?}] Sending query...", stream.peer_addr().unwrap());
+                debug!("[{:?}] Sending query...", target);
 
-                let message = HybridPirMessage::Query(
-                    raidpir_query.clone().into_vec(),
-                    self.sealpir_key().clone(), // TODO
-                    sealpir_query.clone() // TODO
-                );
-                message.write_to(&mut stream)?;
+                let message = QueryRef {
+                    raidpir_query: raidpir_query.as_slice(),
+                    sealpir_query: &sealpir_query,
+                };
+                stream.send(&message, *compression)
+                    .map_err(|e| e.with_peer(*target))?;
 
                 debug!("[{:?}] Sent query ({:.4}ms).",
-                    stream.peer_addr().unwrap(),
+                    target,
                     t2.elapsed().as_secs_f64() * 1000.0);
 
-                match HybridPirMessage::read_from(&mut stream)? {
-                    HybridPirMessage::Response(r) => Ok(r),
-                    _ => Err(Error::new(ErrorKind::Other, "Unexpected Response."))
-                }
+                let mut decoded = self
+                    .receive_replies(&mut stream, None, |_, reply| self.sealpir.decode_reply(sealpir_index, reply))
+                    .map_err(|e| e.with_peer(*target))?;
+
+                Ok(decoded.remove(0))
             })
             .with_max_len(1)
-            .collect::<Result<Vec<PirReply>, Error>>()?;
+            .collect::<Result<Vec<Vec<u8>>, HybridPirError>>()?;
+
+        self.end_cycle(addresses, streams);
+
+        self.combine_decoded(responses)
+    }
+
+    /**
+     * Retrieve several elements in a single round trip. Elements are returned
+     * in the order of `indices`.
+     *
+     * Every server learns how many distinct RaidPIR chunks the indices fall
+     * into, and how many of them are in each chunk, though not which chunks
+     * these are. Unless that's fine, pad batches to a fixed shape with
+     * `set_batch_padding`.
+     */
+    pub fn send_query_batch<A: ToSocketAddrs>(&self, targets: &[A], indices: &[usize]) -> Result<Vec<Vec<u8>>, HybridPirError> {
+        for index in indices {
+            self.check_index(*index)?;
+        }
+
+        self.check_batch(indices)?;
+
+        let addresses = self.resolve(targets)?;
+
+        if indices.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let layout = self.batch_layout(indices);
+        let count = layout.len();
+
+        // Send hello message and retrieve seeds for each server
+        let hello = HybridPirMessage::BatchHello(self.session, self.fingerprint, count as u32, self.compression.clone());
+        let (mut streams, responses) = self.start_cycle(&addresses, &hello)?;
+        let (responses, compression): (Vec<HybridPirMessage>, Vec<Compression>) = responses.into_iter().unzip();
+
+        let seeds: Vec<Vec<u128>> = addresses
+            .iter()
+            .zip(responses.into_iter())
+            .map(|(target, response)| match response {
+                HybridPirMessage::Seeds(s) if s.len() == count => Ok(s),
+                m => Err(HybridPirError::unexpected(&m).with_peer(*target))
+            })
+            .collect::<Result<Vec<Vec<u128>>, HybridPirError>>()?;
+
+        let t1 = Instant::now();
+
+        debug!("Received all seeds, calculating {} queries for {} chunks...",
+            indices.len(), count);
+
+        let (raidpir_queries, sealpir_queries) = self.query_batch(indices, &seeds)?;
+
+        debug!("Calculated queries ({:.4}ms).",
+            t1.elapsed().as_secs_f64() * 1000.0);
+
+        let shape = self.batch_shape(indices);
+        let sealpir_indices = self.flat_sealpir_indices(indices, &layout);
+
+        // Send queries and retrieve responses, decoding them right away
+        let responses: Vec<Vec<Vec<u8>>> = streams
+            .par_iter_mut()
+            .zip(addresses.par_iter().zip(raidpir_queries.into_par_iter()).zip(compression.par_iter()))
+            .map(|(mut stream, ((target, raidpir_queries), compression))| {
+                let message = HybridPirMessage::BatchQuery(raidpir_queries
+                    .into_iter()
+                    .zip(sealpir_queries.iter())
+                    .map(|(raidpir_query, sealpir_queries)| ChunkQuery {
+                        raidpir_query: raidpir_query.into_vec(),
+                        sealpir_queries: sealpir_queries.clone(),
+                    })
+                    .collect());
+                stream.send(&message, *compression)
+                    .map_err(|e| e.with_peer(*target))?;
+
+                self.receive_replies(&mut stream, Some(&shape), |i, reply| self.decode_batch_reply(sealpir_indices[i], reply))
+                    .map_err(|e| e.with_peer(*target))
+            })
+            .with_max_len(1)
+            .collect::<Result<Vec<Vec<Vec<u8>>>, HybridPirError>>()?;
+
+        self.end_cycle(addresses, streams);
+
+        self.combine_batch_decoded(indices, &layout, responses)
+    }
+
+    /**
+     * Look up a key in a database laid out with `layout`, returning its value
+     * or None if it isn't there.
+     *
+     * Every candidate bucket gets its own query, so that the servers can't
+     * tell if any of them ended up in the same RaidPIR chunk, which a batch
+     * query would reveal.
+     */
+    pub fn send_keyword_query<A: ToSocketAddrs>(&self,
+        targets: &[A],
+        layout: &KeywordLayout,
+        key: &[u8]
+    ) -> Result<Option<Vec<u8>>, HybridPirError> {
+        if layout.buckets != self.params.db_len
+            || layout.element_size() != self.params.element_size
+            || self.params.variable_length
+        {
+            return Err(HybridPirError::InvalidParameters(
+                "Keyword layout doesn't match the database.".into()));
+        }
+
+        let records = layout
+            .indices(key)
+            .into_iter()
+            .map(|index| self.send_query(targets, index))
+            .collect::<Result<Vec<Vec<u8>>, HybridPirError>>()?;
+
+        Ok(layout.find(key, &records))
+    }
+
+    /**
+     * Close all connections kept open between queries. This also happens
+     * automatically when the client is dropped.
+     */
+    pub fn close(&self) {
+        let connections: Vec<(SocketAddr, Stream)> = self.connections
+            .lock()
+            .unwrap()
+            .drain()
+            .collect();
+
+        for (target, mut stream) in connections {
+            debug!("[{:?}] Closing connection...", target);
+
+            stream.send(&HybridPirMessage::Close, Compression::None).ok();
+            stream.shutdown();
+        }
+    }
+}
 
-        Ok(self.combine(index, responses))
+impl Drop for HybridPirClient<'_> {
+    fn drop(&mut self) {
+        self.close();
     }
 }
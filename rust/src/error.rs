@@ -0,0 +1,306 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+
+use crate::params::ParamsFingerprint;
+use crate::types::{ErrorCode, HybridPirMessage};
+
+#[derive(Debug)]
+pub enum HybridPirError {
+    /// Parameters are inconsistent with each other or with the database.
+    InvalidParameters(String),
+    /// Requested index lies outside of the database.
+    IndexOutOfRange {
+        index: usize,
+        db_len: usize,
+    },
+    /// A record doesn't fit into a database element.
+    RecordTooLarge {
+        index: usize,
+        size: usize,
+        max: usize,
+    },
+    /// A server address could not be resolved.
+    Resolution(String),
+    /// Reading from or writing to a peer failed.
+    Io {
+        peer: Option<SocketAddr>,
+        source: Error,
+    },
+    /// A peer sent a message that doesn't fit the protocol.
+    Protocol {
+        peer: Option<SocketAddr>,
+        reason: String,
+    },
+    /// A received message could not be deserialized.
+    Deserialization {
+        peer: Option<SocketAddr>,
+        reason: String,
+    },
+    /// Client and server were set up with different parameters.
+    ParamsMismatch {
+        peer: Option<SocketAddr>,
+        local: ParamsFingerprint,
+        remote: ParamsFingerprint,
+    },
+    /// The server is at its connection limit and turned us away.
+    Overloaded {
+        peer: Option<SocketAddr>,
+    },
+    /// The server didn't accept our credentials, or we had none for it.
+    Unauthorized {
+        peer: Option<SocketAddr>,
+    },
+    /// We used up our query quota on the server.
+    RateLimited {
+        peer: Option<SocketAddr>,
+    },
+    /// The server ran into an error and told us about it.
+    Server {
+        peer: Option<SocketAddr>,
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl HybridPirError {
+    pub(crate) fn protocol<S: Into<String>>(reason: S) -> Self {
+        HybridPirError::Protocol {
+            peer: None,
+            reason: reason.into(),
+        }
+    }
+
+    pub(crate) fn unexpected(message: &HybridPirMessage) -> Self {
+        Self::protocol(format!("Unexpected message: {}.", message.name()))
+    }
+
+    /**
+     * The error a server reported in an error reply.
+     */
+    pub(crate) fn reply(code: ErrorCode, message: String) -> Self {
+        match code {
+            ErrorCode::Overloaded => HybridPirError::Overloaded { peer: None },
+            ErrorCode::Unauthorized => HybridPirError::Unauthorized { peer: None },
+            ErrorCode::RateLimited => HybridPirError::RateLimited { peer: None },
+            code => HybridPirError::Server {
+                peer: None,
+                code,
+                message,
+            },
+        }
+    }
+
+    /**
+     * The code a server reports this error to the client with. None for
+     * errors the client can't be told about, because the connection is gone,
+     * or that have a reply of their own.
+     */
+    pub(crate) fn code(&self) -> Option<ErrorCode> {
+        match self {
+            HybridPirError::Io { .. } => None,
+            HybridPirError::ParamsMismatch { .. } => None,
+            HybridPirError::Server { .. } => None,
+            HybridPirError::Protocol { .. } => Some(ErrorCode::Protocol),
+            HybridPirError::Deserialization { .. } => Some(ErrorCode::Protocol),
+            HybridPirError::Overloaded { .. } => Some(ErrorCode::Overloaded),
+            HybridPirError::Unauthorized { .. } => Some(ErrorCode::Unauthorized),
+            HybridPirError::RateLimited { .. } => Some(ErrorCode::RateLimited),
+            _ => Some(ErrorCode::Internal),
+        }
+    }
+
+    /**
+     * Attach the address of the peer an error occurred with, unless it already
+     * has one.
+     */
+    pub fn with_peer(self, address: SocketAddr) -> Self {
+        match self {
+            HybridPirError::Io { peer: None, source } => HybridPirError::Io {
+                peer: Some(address),
+                source
+            },
+            HybridPirError::Protocol { peer: None, reason } => HybridPirError::Protocol {
+                peer: Some(address),
+                reason
+            },
+            HybridPirError::Deserialization { peer: None, reason } => HybridPirError::Deserialization {
+                peer: Some(address),
+                reason
+            },
+            HybridPirError::ParamsMismatch { peer: None, local, remote } => HybridPirError::ParamsMismatch {
+                peer: Some(address),
+                local,
+                remote
+            },
+            HybridPirError::Overloaded { peer: None } => HybridPirError::Overloaded {
+                peer: Some(address)
+            },
+            HybridPirError::Unauthorized { peer: None } => HybridPirError::Unauthorized {
+                peer: Some(address)
+            },
+            HybridPirError::RateLimited { peer: None } => HybridPirError::RateLimited {
+                peer: Some(address)
+            },
+            HybridPirError::Server { peer: None, code, message } => HybridPirError::Server {
+                peer: Some(address),
+                code,
+                message
+            },
+            e => e
+        }
+    }
+
+    /**
+     * The peer an error occurred with, if any.
+     */
+    pub fn peer(&self) -> Option<SocketAddr> {
+        match self {
+            HybridPirError::Io { peer, .. } => *peer,
+            HybridPirError::Protocol { peer, .. } => *peer,
+            HybridPirError::Deserialization { peer, .. } => *peer,
+            HybridPirError::ParamsMismatch { peer, .. } => *peer,
+            HybridPirError::Overloaded { peer } => *peer,
+            HybridPirError::Unauthorized { peer } => *peer,
+            HybridPirError::RateLimited { peer } => *peer,
+            HybridPirError::Server { peer, .. } => *peer,
+            _ => None
+        }
+    }
+
+    /**
+     * Whether this error just means the connection was closed or timed out,
+     * rather than something actually going wrong.
+     */
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            HybridPirError::Io { source, .. } => {
+                source.kind() == ErrorKind::UnexpectedEof
+                    || source.kind() == ErrorKind::WouldBlock
+                    || source.kind() == ErrorKind::TimedOut
+                    || source.kind() == ErrorKind::ConnectionReset
+                    || source.kind() == ErrorKind::ConnectionAborted
+                    || source.kind() == ErrorKind::BrokenPipe
+            },
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for HybridPirError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HybridPirError::InvalidParameters(reason) => {
+                write!(f, "Invalid parameters: {}", reason)
+            },
+            HybridPirError::IndexOutOfRange { index, db_len } => {
+                write!(f, "Index {} out of range for database of {} elements.", index, db_len)
+            },
+            HybridPirError::RecordTooLarge { index, size, max } => {
+                write!(f, "Record {} is {} bytes, at most {} allowed.", index, size, max)
+            },
+            HybridPirError::Resolution(reason) => {
+                write!(f, "Could not resolve address: {}", reason)
+            },
+            HybridPirError::Io { peer: Some(peer), source } => {
+                write!(f, "[{}] I/O error: {}", peer, source)
+            },
+            HybridPirError::Io { peer: None, source } => {
+                write!(f, "I/O error: {}", source)
+            },
+            HybridPirError::Protocol { peer: Some(peer), reason } => {
+                write!(f, "[{}] Protocol violation: {}", peer, reason)
+            },
+            HybridPirError::Protocol { peer: None, reason } => {
+                write!(f, "Protocol violation: {}", reason)
+            },
+            HybridPirError::Deserialization { peer: Some(peer), reason } => {
+                write!(f, "[{}] Invalid message: {}", peer, reason)
+            },
+            HybridPirError::Deserialization { peer: None, reason } => {
+                write!(f, "Invalid message: {}", reason)
+            },
+            HybridPirError::ParamsMismatch { peer: Some(peer), local, remote } => {
+                write!(f, "[{}] Parameter mismatch: {} here, {} on peer.", peer, local, remote)
+            },
+            HybridPirError::ParamsMismatch { peer: None, local, remote } => {
+                write!(f, "Parameter mismatch: {} here, {} on peer.", local, remote)
+            },
+            HybridPirError::Overloaded { peer: Some(peer) } => {
+                write!(f, "[{}] Server overloaded, try again later.", peer)
+            },
+            HybridPirError::Overloaded { peer: None } => {
+                write!(f, "Server overloaded, try again later.")
+            },
+            HybridPirError::Unauthorized { peer: Some(peer) } => {
+                write!(f, "[{}] Not authorized.", peer)
+            },
+            HybridPirError::Unauthorized { peer: None } => {
+                write!(f, "Not authorized.")
+            },
+            HybridPirError::RateLimited { peer: Some(peer) } => {
+                write!(f, "[{}] Query quota exceeded, try again later.", peer)
+            },
+            HybridPirError::RateLimited { peer: None } => {
+                write!(f, "Query quota exceeded, try again later.")
+            },
+            HybridPirError::Server { peer: Some(peer), message, .. } => {
+                write!(f, "[{}] Server reported: {}", peer, message)
+            },
+            HybridPirError::Server { peer: None, message, .. } => {
+                write!(f, "Server reported: {}", message)
+            },
+        }
+    }
+}
+
+impl std::error::Error for HybridPirError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HybridPirError::Io { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+impl From<Error> for HybridPirError {
+    fn from(e: Error) -> Self {
+        HybridPirError::Io {
+            peer: None,
+            source: e,
+        }
+    }
+}
+
+impl From<bincode::Error> for HybridPirError {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(e) => e.into(),
+            e => HybridPirError::Deserialization {
+                peer: None,
+                reason: format!("{}", e),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl From<ciborium::de::Error<Error>> for HybridPirError {
+    fn from(e: ciborium::de::Error<Error>) -> Self {
+        // Bodies are read from memory, running out just means it's truncated
+        HybridPirError::Deserialization {
+            peer: None,
+            reason: format!("{}", e),
+        }
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl From<ciborium::ser::Error<Error>> for HybridPirError {
+    fn from(e: ciborium::ser::Error<Error>) -> Self {
+        match e {
+            ciborium::ser::Error::Io(e) => e.into(),
+            ciborium::ser::Error::Value(reason) => HybridPirError::protocol(reason),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::HybridPirError;
+use crate::types::fnv1a;
+
+/// Number of hash functions, i.e. candidate buckets every key may live in.
+/// This is also the number of index queries a lookup takes.
+pub const NUM_HASHES: usize = 3;
+
+/// Buckets per entry. Cuckoo hashing with three hash functions reliably
+/// succeeds below a load of about 90%.
+const EXPANSION: f64 = 1.3;
+
+/// Evictions before giving up on a seed and starting over with the next one.
+const MAX_EVICTIONS: usize = 1000;
+
+/// Seeds to try before giving up on building the table entirely.
+const MAX_SEEDS: u64 = 16;
+
+/// Size of the key tag at the start of every record.
+const TAG_SIZE: usize = 16;
+
+/// Size of the value length following the tag.
+const LENGTH_SIZE: usize = 4;
+
+/**
+ * How (key, value) pairs are laid out in a HybridPIR database using cuckoo
+ * hashing. Every key lives in one of `NUM_HASHES` buckets, so clients find it
+ * by querying all of them.
+ *
+ * Every bucket holds a single record, consisting of a 128 bit tag derived
+ * from the key, the length of the value and the value itself padded to
+ * `value_size` bytes. Empty buckets are all zeros.
+ *
+ * The layout has to be shared with clients, and every server has to build its
+ * database from the same entries, in the same order.
+ *
+ * ```
+ * use hybridpir::keyword::KeywordLayout;
+ *
+ * let entries = vec![
+ *     (b"alice".to_vec(), b"1234".to_vec()),
+ *     (b"bob".to_vec(), b"56".to_vec()),
+ * ];
+ *
+ * let (layout, db) = KeywordLayout::build(&entries).unwrap();
+ * assert!(db.len() == layout.buckets);
+ * assert!(db.iter().all(|r| r.len() == layout.element_size()));
+ *
+ * let records: Vec<Vec<u8>> = layout.indices(b"bob").iter().map(|i| db[*i].clone()).collect();
+ * assert!(layout.find(b"bob", &records) == Some(b"56".to_vec()));
+ * assert!(layout.find(b"carol", &records) == None);
+ * ```
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeywordLayout {
+    pub buckets: usize,
+    pub value_size: usize,
+    pub seed: u64,
+}
+
+impl KeywordLayout {
+    /**
+     * Lay out the given (key, value) pairs, returning the layout and the
+     * database to set up HybridPIR servers with.
+     */
+    pub fn build(entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(Self, Vec<Vec<u8>>), HybridPirError> {
+        if entries.is_empty() {
+            return Err(HybridPirError::InvalidParameters("No entries given.".into()));
+        }
+
+        let mut keys: HashSet<&[u8]> = HashSet::with_capacity(entries.len());
+        if let Some((key, _)) = entries.iter().find(|(key, _)| !keys.insert(key.as_slice())) {
+            return Err(HybridPirError::InvalidParameters(
+                format!("Duplicate key {:02x?}.", key)));
+        }
+
+        let value_size = entries.iter().map(|(_, value)| value.len()).max().unwrap_or(0);
+        if value_size > u32::MAX as usize {
+            return Err(HybridPirError::InvalidParameters("Values too large.".into()));
+        }
+
+        let buckets = ((entries.len() as f64 * EXPANSION).ceil() as usize).max(NUM_HASHES);
+
+        for seed in 0..MAX_SEEDS {
+            let layout = Self { buckets, value_size, seed };
+
+            if let Some(table) = layout.place(entries) {
+                let db = table
+                    .into_iter()
+                    .map(|slot| match slot {
+                        Some(i) => layout.record(&entries[i].0, &entries[i].1),
+                        None => vec![0; layout.element_size()],
+                    })
+                    .collect();
+
+                return Ok((layout, db));
+            }
+
+            debug!("Cuckoo hashing failed with seed {}, retrying...", seed);
+        }
+
+        Err(HybridPirError::InvalidParameters("Could not place all entries.".into()))
+    }
+
+    /**
+     * Size of every database element under this layout.
+     */
+    pub fn element_size(&self) -> usize {
+        TAG_SIZE + LENGTH_SIZE + self.value_size
+    }
+
+    /**
+     * Buckets the given key may live in. To not reveal anything about the
+     * key, all of them have to be queried, even if the key was already
+     * found.
+     */
+    pub fn indices(&self, key: &[u8]) -> Vec<usize> {
+        (0..NUM_HASHES)
+            .map(|i| (self.hash(i as u64, key) % self.buckets as u64) as usize)
+            .collect()
+    }
+
+    /**
+     * Look for the given key in the records retrieved from its buckets,
+     * returning its value if it's there.
+     */
+    pub fn find(&self, key: &[u8], records: &[Vec<u8>]) -> Option<Vec<u8>> {
+        let tag = self.tag(key);
+
+        records
+            .iter()
+            .filter(|r| r.len() == self.element_size() && r[..TAG_SIZE] == tag[..])
+            .find_map(|r| {
+                let mut length = [0; LENGTH_SIZE];
+                length.copy_from_slice(&r[TAG_SIZE..TAG_SIZE + LENGTH_SIZE]);
+                let length = u32::from_le_bytes(length) as usize;
+
+                r[TAG_SIZE + LENGTH_SIZE..].get(..length).map(|v| v.to_vec())
+            })
+    }
+
+    fn hash(&self, domain: u64, key: &[u8]) -> u64 {
+        let mut data = Vec::with_capacity(16 + key.len());
+        data.extend_from_slice(&self.seed.to_le_bytes());
+        data.extend_from_slice(&domain.to_le_bytes());
+        data.extend_from_slice(key);
+
+        // FNV-1a doesn't mix its upper bits well, so finish with splitmix64
+        let mut h = fnv1a(&data);
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+        h ^ (h >> 31)
+    }
+
+    fn tag(&self, key: &[u8]) -> [u8; TAG_SIZE] {
+        let mut tag = [0; TAG_SIZE];
+        tag[..8].copy_from_slice(&self.hash(NUM_HASHES as u64, key).to_le_bytes());
+        tag[8..].copy_from_slice(&self.hash(NUM_HASHES as u64 + 1, key).to_le_bytes());
+        tag
+    }
+
+    fn record(&self, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut record = Vec::with_capacity(self.element_size());
+        record.extend_from_slice(&self.tag(key));
+        record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        record.extend_from_slice(value);
+        record.resize(self.element_size(), 0);
+        record
+    }
+
+    /**
+     * Run cuckoo hashing, returning the entry placed in every bucket, or None
+     * if some entry couldn't be placed with this seed.
+     */
+    fn place(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Option<Vec<Option<usize>>> {
+        let candidates: Vec<Vec<usize>> = entries.iter().map(|(key, _)| self.indices(key)).collect();
+        let mut table: Vec<Option<usize>> = vec![None; self.buckets];
+
+        // Deterministic, so every server ends up with the same table
+        let mut state = self.seed ^ 0x9e3779b97f4a7c15;
+
+        for entry in 0..entries.len() {
+            let mut current = entry;
+            let mut placed = false;
+
+            for _ in 0..MAX_EVICTIONS {
+                if let Some(bucket) = candidates[current].iter().find(|b| table[**b].is_none()) {
+                    table[*bucket] = Some(current);
+                    placed = true;
+                    break;
+                }
+
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+
+                let bucket = candidates[current][(state % NUM_HASHES as u64) as usize];
+                current = table[bucket].replace(current).unwrap();
+            }
+
+            if !placed {
+                return None;
+            }
+        }
+
+        Some(table)
+    }
+}
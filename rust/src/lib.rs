@@ -5,6 +5,21 @@ extern crate log;
 #[allow(non_snake_case)]
 pub mod android;
 
+pub mod auth;
+pub mod error;
+pub mod keyword;
+pub mod metrics;
+pub mod params;
+pub mod planner;
 pub mod server;
 pub mod client;
 pub mod types;
+
+mod stream;
+mod streaming;
+
+#[cfg(feature = "async")]
+pub mod asynchronous;
+
+#[cfg(feature = "tls")]
+pub mod tls;
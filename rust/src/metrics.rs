@@ -0,0 +1,334 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::error::HybridPirError;
+use crate::stream::Stream;
+use crate::types::{Compression, Frame, HybridPirMessage, MessageType, SizeLimits};
+
+/// Upper bounds of the latency histogram buckets, in seconds. Anything slower
+/// ends up in an extra bucket at the end.
+pub const LATENCY_BUCKETS: [f64; 16] = [
+    0.0001, 0.00025, 0.0005,
+    0.001, 0.0025, 0.005,
+    0.01, 0.025, 0.05,
+    0.1, 0.25, 0.5,
+    1.0, 2.5, 5.0,
+    10.0,
+];
+
+/**
+ * Steps of a query cycle, as seen by the server.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Receiving the hello message.
+    Hello,
+    /// Asking for and receiving the client's SealPIR key.
+    KeyRegistration,
+    /// Taking seeds from the RaidPIR queue and sending them.
+    Seed,
+    /// Receiving the query.
+    Query,
+    /// Calculating the response.
+    Response,
+    /// Sending the response.
+    Send,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 6] = [
+        Phase::Hello,
+        Phase::KeyRegistration,
+        Phase::Seed,
+        Phase::Query,
+        Phase::Response,
+        Phase::Send,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Hello => "hello",
+            Phase::KeyRegistration => "key_registration",
+            Phase::Seed => "seed",
+            Phase::Query => "query",
+            Phase::Response => "response",
+            Phase::Send => "send",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn record(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramStats {
+        HistogramStats {
+            buckets: self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/**
+ * Latencies recorded for one phase. `buckets` holds the number of samples
+ * falling into each of `LATENCY_BUCKETS`, plus one for anything slower.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramStats {
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+/**
+ * Messages of one type received and sent, and their total size on the wire.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageStats {
+    pub received: u64,
+    pub received_bytes: u64,
+    pub sent: u64,
+    pub sent_bytes: u64,
+}
+
+/**
+ * Everything a running server has recorded so far, see
+ * `ServerHandle::stats`. Formatting it gives the Prometheus text format
+ * served on `ServerConfig::metrics_addr`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub phases: Vec<(Phase, HistogramStats)>,
+    pub messages: BTreeMap<MessageType, MessageStats>,
+    pub connections: u64,
+    pub active_connections: usize,
+    pub rejected_connections: u64,
+    pub queue_depth: usize,
+    pub errors: BTreeMap<&'static str, u64>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# TYPE hybridpir_phase_seconds histogram")?;
+        for (phase, histogram) in self.phases.iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                writeln!(f, "hybridpir_phase_seconds_bucket{{phase=\"{}\",le=\"{}\"}} {}",
+                    phase.name(), bound, cumulative)?;
+            }
+            writeln!(f, "hybridpir_phase_seconds_bucket{{phase=\"{}\",le=\"+Inf\"}} {}",
+                phase.name(), histogram.count)?;
+            writeln!(f, "hybridpir_phase_seconds_sum{{phase=\"{}\"}} {}",
+                phase.name(), histogram.sum.as_secs_f64())?;
+            writeln!(f, "hybridpir_phase_seconds_count{{phase=\"{}\"}} {}",
+                phase.name(), histogram.count)?;
+        }
+
+        writeln!(f, "# TYPE hybridpir_messages_total counter")?;
+        for (message_type, stats) in self.messages.iter() {
+            writeln!(f, "hybridpir_messages_total{{type=\"{}\",direction=\"in\"}} {}", message_type.name(), stats.received)?;
+            writeln!(f, "hybridpir_messages_total{{type=\"{}\",direction=\"out\"}} {}", message_type.name(), stats.sent)?;
+        }
+
+        writeln!(f, "# TYPE hybridpir_message_bytes_total counter")?;
+        for (message_type, stats) in self.messages.iter() {
+            writeln!(f, "hybridpir_message_bytes_total{{type=\"{}\",direction=\"in\"}} {}", message_type.name(), stats.received_bytes)?;
+            writeln!(f, "hybridpir_message_bytes_total{{type=\"{}\",direction=\"out\"}} {}", message_type.name(), stats.sent_bytes)?;
+        }
+
+        writeln!(f, "# TYPE hybridpir_connections_total counter")?;
+        writeln!(f, "hybridpir_connections_total {}", self.connections)?;
+        writeln!(f, "# TYPE hybridpir_active_connections gauge")?;
+        writeln!(f, "hybridpir_active_connections {}", self.active_connections)?;
+        writeln!(f, "# TYPE hybridpir_rejected_connections_total counter")?;
+        writeln!(f, "hybridpir_rejected_connections_total {}", self.rejected_connections)?;
+        writeln!(f, "# TYPE hybridpir_queue_depth gauge")?;
+        writeln!(f, "hybridpir_queue_depth {}", self.queue_depth)?;
+
+        writeln!(f, "# TYPE hybridpir_errors_total counter")?;
+        for (kind, count) in self.errors.iter() {
+            writeln!(f, "hybridpir_errors_total{{kind=\"{}\"}} {}", kind, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * Counters shared by all threads of a running server.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    phases: [Histogram; Phase::ALL.len()],
+    messages: Mutex<BTreeMap<MessageType, MessageStats>>,
+    connections: AtomicU64,
+    active_connections: AtomicUsize,
+    rejected_connections: AtomicU64,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    pub(crate) fn record(&self, phase: Phase, elapsed: Duration) {
+        let index = Phase::ALL.iter().position(|p| *p == phase).unwrap();
+        self.phases[index].record(elapsed);
+    }
+
+    /**
+     * Read a message within the given limits, counting it and its size. Like
+     * `Stream::receive`, replies are sent in its encoding from now on.
+     */
+    pub(crate) fn receive(&self, stream: &mut Stream, limits: &SizeLimits) -> Result<HybridPirMessage, HybridPirError> {
+        let mut counting = Counting { inner: stream, bytes: 0 };
+        let (message, encoding) = HybridPirMessage::read_frame(&mut counting, limits)?;
+        let bytes = counting.bytes;
+
+        stream.encoding = encoding;
+        self.received(message.message_type(), bytes);
+
+        Ok(message)
+    }
+
+    /**
+     * Count a message of the given size that was read some other way.
+     */
+    pub(crate) fn received(&self, message_type: MessageType, bytes: u64) {
+        let mut messages = self.messages.lock().unwrap();
+        let stats = messages.entry(message_type).or_default();
+        stats.received += 1;
+        stats.received_bytes += bytes;
+    }
+
+    /**
+     * Write a message, counting it and its size.
+     */
+    pub(crate) fn send(&self, stream: &mut Stream, message: &HybridPirMessage) -> Result<(), HybridPirError> {
+        self.send_compressed(stream, message, Compression::None)
+    }
+
+    /**
+     * Write a message, compressed if large enough, counting it and its size
+     * on the wire.
+     */
+    pub(crate) fn send_compressed(&self,
+        stream: &mut Stream,
+        message: &HybridPirMessage,
+        compression: Compression
+    ) -> Result<(), HybridPirError> {
+        let encoding = stream.encoding;
+        let mut counting = Counting { inner: stream, bytes: 0 };
+        message.write_encoded(&mut counting, encoding, compression)?;
+
+        let bytes = counting.bytes;
+        self.sent(message.message_type(), bytes);
+
+        Ok(())
+    }
+
+    /**
+     * Count a message of the given size that was written some other way.
+     */
+    pub(crate) fn sent(&self, message_type: MessageType, bytes: u64) {
+        let mut messages = self.messages.lock().unwrap();
+        let stats = messages.entry(message_type).or_default();
+        stats.sent += 1;
+        stats.sent_bytes += bytes;
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_rejected(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn error(&self, error: &HybridPirError) {
+        let kind = match error {
+            HybridPirError::InvalidParameters(_) => "invalid_parameters",
+            HybridPirError::IndexOutOfRange { .. } => "index_out_of_range",
+            HybridPirError::RecordTooLarge { .. } => "record_too_large",
+            HybridPirError::Resolution(_) => "resolution",
+            HybridPirError::Io { .. } => "io",
+            HybridPirError::Protocol { .. } => "protocol",
+            HybridPirError::Deserialization { .. } => "deserialization",
+            HybridPirError::ParamsMismatch { .. } => "params_mismatch",
+            HybridPirError::Overloaded { .. } => "overloaded",
+            HybridPirError::Unauthorized { .. } => "unauthorized",
+            HybridPirError::RateLimited { .. } => "rate_limited",
+            HybridPirError::Server { .. } => "server",
+        };
+
+        *self.errors.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    pub(crate) fn stats(&self, queue_depth: usize) -> Stats {
+        Stats {
+            phases: Phase::ALL
+                .iter()
+                .zip(self.phases.iter())
+                .map(|(phase, histogram)| (*phase, histogram.snapshot()))
+                .collect(),
+            messages: self.messages.lock().unwrap().clone(),
+            connections: self.connections.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            rejected_connections: self.rejected_connections.load(Ordering::Relaxed),
+            queue_depth,
+            errors: self.errors.lock().unwrap().clone(),
+        }
+    }
+}
+
+/**
+ * Passes reads and writes through, keeping track of how many bytes went by.
+ */
+struct Counting<'a, S> {
+    inner: &'a mut S,
+    bytes: u64,
+}
+
+impl<'a, S: Read> Read for Counting<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a, S: Write> Write for Counting<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,322 @@
+use std::fmt;
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::HybridPirError;
+use crate::types::fnv1a;
+
+/// Size of the length header in front of every record, if records have
+/// variable length.
+pub const LENGTH_HEADER_SIZE: usize = 4;
+
+/**
+ * Parameters shared by HybridPIR clients and servers. Both sides have to be
+ * built from the same parameters, otherwise the client will decode garbage.
+ *
+ * ```
+ * use hybridpir::params::HybridPirParams;
+ *
+ * let params = HybridPirParams::builder(1 << 12, 8)
+ *     .raidpir_servers(2)
+ *     .raidpir_redundancy(2)
+ *     .raidpir_size(1 << 8)
+ *     .sealpir(2048, 12, 2)
+ *     .build()
+ *     .unwrap();
+ *
+ * assert!(params.raidpir_chunksize() == 16);
+ * ```
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HybridPirParams {
+    pub db_len: usize,
+    pub element_size: usize,
+    pub raidpir_servers: usize,
+    pub raidpir_redundancy: usize,
+    pub raidpir_size: usize,
+    /// Only relevant to servers, doesn't affect responses.
+    pub raidpir_russians: bool,
+    pub sealpir_poly_degree: u32,
+    pub sealpir_log: u32,
+    pub sealpir_d: u32,
+    /// Records may be shorter than `element_size`. They're stored with a
+    /// length header and padded, and clients get back the original bytes.
+    #[serde(default)]
+    pub variable_length: bool,
+}
+
+impl HybridPirParams {
+    pub fn builder(db_len: usize, element_size: usize) -> HybridPirParamsBuilder {
+        HybridPirParamsBuilder::new(db_len, element_size)
+    }
+
+    /**
+     * Number of database elements in every RaidPIR chunk, which is what the
+     * SealPIR part of a query is run on.
+     */
+    pub fn raidpir_chunksize(&self) -> usize {
+        (self.db_len + self.raidpir_size - 1) / self.raidpir_size
+    }
+
+    /**
+     * Largest record that can be stored with these parameters.
+     */
+    pub fn max_record_size(&self) -> usize {
+        if self.variable_length {
+            self.element_size.saturating_sub(LENGTH_HEADER_SIZE)
+        } else {
+            self.element_size
+        }
+    }
+
+    /**
+     * Append a record to `out` the way it's stored in the database, i.e. with
+     * length header and padding if records have variable length.
+     */
+    pub(crate) fn encode_record(&self,
+        index: usize,
+        record: &[u8],
+        out: &mut Vec<u8>
+    ) -> Result<(), HybridPirError> {
+        if !self.variable_length {
+            if record.len() != self.element_size {
+                return Err(HybridPirError::InvalidParameters(
+                    format!("Element {} is not {} bytes in size.", index, self.element_size)));
+            }
+
+            out.extend_from_slice(record);
+            return Ok(());
+        }
+
+        if record.len() > self.max_record_size() {
+            return Err(HybridPirError::RecordTooLarge {
+                index,
+                size: record.len(),
+                max: self.max_record_size(),
+            });
+        }
+
+        let start = out.len();
+        out.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        out.extend_from_slice(record);
+        out.resize(start + self.element_size, 0);
+
+        Ok(())
+    }
+
+    /**
+     * Recover a record from a database element, stripping length header and
+     * padding if records have variable length.
+     */
+    pub(crate) fn decode_record(&self, mut element: Vec<u8>) -> Result<Vec<u8>, HybridPirError> {
+        if !self.variable_length {
+            return Ok(element);
+        }
+
+        if element.len() != self.element_size {
+            return Err(HybridPirError::protocol(
+                format!("Element is {} bytes, expected {}.", element.len(), self.element_size)));
+        }
+
+        let mut header = [0; LENGTH_HEADER_SIZE];
+        header.copy_from_slice(&element[..LENGTH_HEADER_SIZE]);
+        let size = u32::from_le_bytes(header) as usize;
+
+        if size > self.max_record_size() {
+            return Err(HybridPirError::protocol(
+                format!("Record claims to be {} bytes, at most {} allowed.", size, self.max_record_size())));
+        }
+
+        element.truncate(LENGTH_HEADER_SIZE + size);
+        element.drain(..LENGTH_HEADER_SIZE);
+        Ok(element)
+    }
+
+    /**
+     * Summarize these parameters for the handshake, so servers can turn away
+     * clients that were set up differently. Whether servers use the method of
+     * four russians doesn't change their responses and is left out.
+     *
+     * ```
+     * use hybridpir::params::HybridPirParams;
+     *
+     * let a = HybridPirParams::builder(1 << 12, 8).raidpir_size(1 << 8).build().unwrap();
+     * let b = HybridPirParams::builder(1 << 12, 8).raidpir_size(1 << 7).build().unwrap();
+     *
+     * assert!(a.fingerprint() == a.clone().fingerprint());
+     * assert!(a.fingerprint() != b.fingerprint());
+     * ```
+     */
+    pub fn fingerprint(&self) -> ParamsFingerprint {
+        let fields = [
+            self.db_len as u64,
+            self.element_size as u64,
+            self.raidpir_servers as u64,
+            self.raidpir_redundancy as u64,
+            self.raidpir_size as u64,
+            self.sealpir_poly_degree as u64,
+            self.sealpir_log as u64,
+            self.sealpir_d as u64,
+            self.variable_length as u64,
+        ];
+
+        let bytes: Vec<u8> = fields.iter().flat_map(|f| f.to_le_bytes().to_vec()).collect();
+
+        ParamsFingerprint {
+            db_len: self.db_len as u64,
+            element_size: self.element_size as u64,
+            hash: fnv1a(&bytes),
+        }
+    }
+
+    /**
+     * Check the constraints HybridPIR places on the database shape, RaidPIR
+     * and SealPIR parameters.
+     *
+     * ```
+     * use hybridpir::params::HybridPirParams;
+     *
+     * let mut params = HybridPirParams::builder(1 << 12, 8)
+     *     .raidpir_size(1 << 8)
+     *     .build()
+     *     .unwrap();
+     *
+     * params.raidpir_size = 100;
+     * assert!(params.validate().is_err());
+     * ```
+     */
+    pub fn validate(&self) -> Result<(), HybridPirError> {
+        if self.db_len == 0 || self.element_size == 0 {
+            return Err(HybridPirError::InvalidParameters("Database must not be empty.".into()));
+        }
+
+        if self.raidpir_servers < 2 {
+            return Err(HybridPirError::InvalidParameters("At least two servers are required.".into()));
+        }
+
+        if self.raidpir_redundancy == 0 || self.raidpir_redundancy > self.raidpir_servers {
+            return Err(HybridPirError::InvalidParameters(
+                format!("Redundancy must be between 1 and {}.", self.raidpir_servers)));
+        }
+
+        if self.raidpir_size == 0 || self.raidpir_size >= self.db_len {
+            return Err(HybridPirError::InvalidParameters(
+                format!("RaidPIR size must be between 1 and {}.", self.db_len - 1)));
+        }
+
+        if self.raidpir_size % (self.raidpir_servers * 8) != 0 {
+            return Err(HybridPirError::InvalidParameters(
+                format!("RaidPIR size must be a multiple of {}.", self.raidpir_servers * 8)));
+        }
+
+        if !self.sealpir_poly_degree.is_power_of_two() || self.sealpir_poly_degree < 1024 {
+            return Err(HybridPirError::InvalidParameters(
+                "SealPIR polynomial degree must be a power of two, at least 1024.".into()));
+        }
+
+        if self.sealpir_log == 0 || self.sealpir_log >= 60 {
+            return Err(HybridPirError::InvalidParameters(
+                "SealPIR plaintext modulus must be between 1 and 59 bits.".into()));
+        }
+
+        if self.sealpir_d == 0 {
+            return Err(HybridPirError::InvalidParameters(
+                "SealPIR dimension must be at least 1.".into()));
+        }
+
+        if self.variable_length && self.element_size <= LENGTH_HEADER_SIZE {
+            return Err(HybridPirError::InvalidParameters(
+                format!("Elements must be larger than the {} byte length header.", LENGTH_HEADER_SIZE)));
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * What client and server tell each other about their parameters during the
+ * handshake. The database shape is sent in the clear so mismatches can be
+ * reported in a useful way, everything else only goes into the hash.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParamsFingerprint {
+    pub db_len: u64,
+    pub element_size: u64,
+    pub hash: u64,
+}
+
+impl fmt::Display for ParamsFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} x {} bytes ({:016x})", self.db_len, self.element_size, self.hash)
+    }
+}
+
+/**
+ * Builder for HybridPirParams. Only the database shape and RaidPIR size have
+ * to be given, everything else defaults to two servers with full redundancy
+ * and SealPIR parameters suitable for small elements.
+ */
+#[derive(Debug, Clone)]
+pub struct HybridPirParamsBuilder {
+    params: HybridPirParams,
+}
+
+impl HybridPirParamsBuilder {
+    pub fn new(db_len: usize, element_size: usize) -> Self {
+        Self {
+            params: HybridPirParams {
+                db_len,
+                element_size,
+                raidpir_servers: 2,
+                raidpir_redundancy: 2,
+                raidpir_size: 0,
+                raidpir_russians: false,
+                sealpir_poly_degree: 2048,
+                sealpir_log: 12,
+                sealpir_d: 2,
+                variable_length: false,
+            }
+        }
+    }
+
+    pub fn raidpir_servers(mut self, servers: usize) -> Self {
+        self.params.raidpir_servers = servers;
+        self
+    }
+
+    pub fn raidpir_redundancy(mut self, redundancy: usize) -> Self {
+        self.params.raidpir_redundancy = redundancy;
+        self
+    }
+
+    pub fn raidpir_size(mut self, size: usize) -> Self {
+        self.params.raidpir_size = size;
+        self
+    }
+
+    pub fn raidpir_russians(mut self, russians: bool) -> Self {
+        self.params.raidpir_russians = russians;
+        self
+    }
+
+    pub fn sealpir(mut self, poly_degree: u32, log: u32, d: u32) -> Self {
+        self.params.sealpir_poly_degree = poly_degree;
+        self.params.sealpir_log = log;
+        self.params.sealpir_d = d;
+        self
+    }
+
+    /**
+     * Accept records of up to `element_size - LENGTH_HEADER_SIZE` bytes
+     * instead of exactly `element_size`.
+     */
+    pub fn variable_length(mut self, variable_length: bool) -> Self {
+        self.params.variable_length = variable_length;
+        self
+    }
+
+    pub fn build(self) -> Result<HybridPirParams, HybridPirError> {
+        self.params.validate()?;
+        Ok(self.params)
+    }
+}
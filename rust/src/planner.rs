@@ -0,0 +1,211 @@
+use serde::{Serialize, Deserialize};
+
+use crate::error::HybridPirError;
+use crate::params::HybridPirParams;
+
+/**
+ * SealPIR configurations (polynomial degree, plaintext modulus bits,
+ * dimensions) known to leave enough noise budget for replies to decrypt.
+ *
+ * Only degree 2048 is offered. Larger degrees are valid parameters, and the
+ * cost model accounts for their extra moduli, but the plaintext moduli they
+ * can afford depend on the coefficient moduli SEAL picks for them, which
+ * haven't been checked against the SealPIR version we build on. Set them
+ * with `HybridPirParamsBuilder::sealpir` instead where needed.
+ */
+const SEALPIR_CONFIGS: [(u32, u32, u32); 3] = [
+    (2048, 24, 1),
+    (2048, 12, 2),
+    (2048, 12, 3),
+];
+
+/**
+ * Environment a deployment is expected to run in, in bytes, seconds and
+ * bytes per second.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CostModel {
+    /// Client to server bandwidth, shared between all servers.
+    pub upload_bandwidth: f64,
+    /// Server to client bandwidth, shared between all servers.
+    pub download_bandwidth: f64,
+    /// Round trip time to the slowest server.
+    pub round_trip: f64,
+    /// Rate at which a server XORs database chunks for RaidPIR.
+    pub raidpir_throughput: f64,
+    /// Time a server spends on each SealPIR plaintext multiplication, for a
+    /// polynomial degree of 2048.
+    pub sealpir_multiplication: f64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            upload_bandwidth: 10e6,
+            download_bandwidth: 50e6,
+            round_trip: 0.05,
+            raidpir_throughput: 2e9,
+            sealpir_multiplication: 25e-6,
+        }
+    }
+}
+
+/**
+ * Recommended parameters, along with what a single query is predicted to
+ * cost with them. Transfer sizes are totals over all servers, excluding the
+ * one-time upload of the SealPIR key.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub params: HybridPirParams,
+    pub upload: usize,
+    pub download: usize,
+    pub server_time: f64,
+    pub total_time: f64,
+}
+
+/**
+ * Predict the cost of a single query with the given parameters.
+ *
+ * This only models the dominant terms, namely query and reply sizes, the
+ * RaidPIR XOR pass and the SealPIR plaintext multiplications, and assumes the
+ * servers answer in parallel.
+ */
+pub fn estimate(params: &HybridPirParams, model: &CostModel) -> Plan {
+    let n = params.sealpir_poly_degree as usize;
+    let log_t = params.sealpir_log as usize;
+    let d = params.sealpir_d as u32;
+
+    // SEAL uses a single 54 bit modulus for 2048, more for larger degrees,
+    // and serializes every coefficient to 64 bits.
+    let moduli = (n / 2048).max(1);
+    let log_q = 54 * moduli;
+    let ciphertext = 2 * n * moduli * 8;
+
+    // Plaintexts needed to hold a RaidPIR chunk
+    let element_bits = params.element_size * 8;
+    let (per_plaintext, plaintexts_per_element) = if element_bits <= n * log_t {
+        ((n * log_t) / element_bits, 1)
+    } else {
+        (1, (element_bits + n * log_t - 1) / (n * log_t))
+    };
+    let plaintexts = (params.raidpir_chunksize() + per_plaintext - 1) / per_plaintext
+        * plaintexts_per_element;
+
+    // Side length of the SealPIR hypercube
+    let dimension = ((plaintexts as f64).powf(1.0 / d as f64)).ceil() as usize;
+
+    // Every reply ciphertext turns into this many when used as a plaintext
+    // for the next dimension.
+    let expansion = 2 * ((log_q + log_t - 1) / log_t);
+
+    let sealpir_query = d as usize * ((dimension + n - 1) / n) * ciphertext;
+    let sealpir_reply = plaintexts_per_element * expansion.pow(d - 1) * ciphertext;
+    let raidpir_query = params.raidpir_size / 8;
+
+    let upload = params.raidpir_servers * (raidpir_query + sealpir_query);
+    let download = params.raidpir_servers * sealpir_reply;
+
+    let db_bytes = (params.db_len * params.element_size) as f64;
+    let raidpir_time = db_bytes * params.raidpir_redundancy as f64
+        / params.raidpir_servers as f64
+        / model.raidpir_throughput;
+
+    // First dimension multiplies every plaintext, every further one works on
+    // the expanded replies of the previous one.
+    let mut multiplications = plaintexts as f64;
+    let mut remaining = plaintexts as f64;
+    for j in 1..d {
+        remaining /= dimension as f64;
+        multiplications += (expansion as f64).powi(j as i32) * remaining;
+    }
+    let sealpir_time = multiplications * model.sealpir_multiplication * (n as f64 / 2048.0);
+
+    let server_time = raidpir_time + sealpir_time;
+
+    // Hello/seed and query/response round trips
+    let total_time = 2.0 * model.round_trip
+        + upload as f64 / model.upload_bandwidth
+        + download as f64 / model.download_bandwidth
+        + server_time;
+
+    Plan {
+        params: params.clone(),
+        upload,
+        download,
+        server_time,
+        total_time,
+    }
+}
+
+/**
+ * Every valid combination of RaidPIR size and known SealPIR configuration
+ * for the given database, cheapest first. Combinations the cost model can't
+ * put a finite time on are left out.
+ */
+pub fn candidates(
+    db_len: usize,
+    element_size: usize,
+    servers: usize,
+    model: &CostModel,
+) -> Vec<Plan> {
+    let mut plans: Vec<Plan> = Vec::new();
+
+    let mut raidpir_size = servers * 8;
+    while raidpir_size < db_len {
+        for (poly_degree, log, d) in SEALPIR_CONFIGS.iter() {
+            let params = HybridPirParams::builder(db_len, element_size)
+                .raidpir_servers(servers)
+                .raidpir_redundancy(servers)
+                .raidpir_size(raidpir_size)
+                .sealpir(*poly_degree, *log, *d)
+                .build();
+
+            if let Ok(params) = params {
+                let plan = estimate(&params, model);
+
+                if plan.total_time.is_finite() {
+                    plans.push(plan);
+                }
+            }
+        }
+
+        raidpir_size *= 2;
+    }
+
+    // Only finite times left, so these always compare
+    plans.sort_by(|a, b| a.total_time.partial_cmp(&b.total_time).unwrap());
+    plans
+}
+
+/**
+ * Recommend parameters for a database, minimizing the predicted time per
+ * query under the given cost model.
+ *
+ * ```
+ * use hybridpir::planner::{plan, CostModel};
+ *
+ * let plan = plan(1 << 20, 8, 2, &CostModel::default()).unwrap();
+ *
+ * assert!(plan.params.validate().is_ok());
+ * assert!(plan.params.raidpir_size < 1 << 20);
+ * ```
+ */
+pub fn plan(
+    db_len: usize,
+    element_size: usize,
+    servers: usize,
+    model: &CostModel,
+) -> Result<Plan, HybridPirError> {
+    // Surface the actual problem if the database shape itself is invalid
+    HybridPirParams::builder(db_len, element_size)
+        .raidpir_servers(servers)
+        .raidpir_redundancy(servers)
+        .raidpir_size(servers * 8)
+        .build()?;
+
+    candidates(db_len, element_size, servers, model)
+        .into_iter()
+        .next()
+        .ok_or(HybridPirError::InvalidParameters("No valid parameters found.".into()))
+}
@@ -1,199 +1,1785 @@
-use std::io::{Error, ErrorKind};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+#[cfg(not(feature = "mmap"))]
+use std::io::BufReader;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TrySendError};
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
 use bitvec::prelude::*;
+use raidpir::client::RaidPirClient;
 use raidpir::server::RaidPirServer;
 use raidpir::types::RaidPirData;
 use sealpir::server::PirServer;
 use sealpir::{PirQuery, PirReply};
+use rayon::prelude::*;
 
+use crate::auth::{Authorizer, RateLimit, RateLimiter};
+use crate::error::HybridPirError;
+use crate::metrics::{Metrics, Phase, Stats};
+use crate::params::{HybridPirParams, ParamsFingerprint};
+use crate::stream::Stream;
+use crate::streaming::ResponseParts;
 use crate::types::*;
 
+/// Maximum number of SealPIR Galois keys kept in memory at once.
+const MAX_CACHED_KEYS: usize = 256;
+
+/// Maximum number of RaidPIR chunks a single batch query may touch.
+pub(crate) const MAX_BATCH_SIZE: usize = 256;
+
+/// Largest part responses are streamed in by default.
+pub(crate) const RESPONSE_PART_SIZE: usize = 1 << 20;
+
+/**
+ * How `accept_connections` serves clients.
+ *
+ * Every connection is handled by one of `max_connections` worker threads for
+ * as long as it stays open. Connections beyond that wait in a queue of up to
+ * `max_pending`, and anything that doesn't fit in there either is told the
+ * server is busy and closed right away.
+ *
+ * Meanwhile, `preprocess_threads` threads keep `queue_depth` RaidPIR seeds
+ * precomputed. With a depth of zero, each connection instead refills the
+ * queue itself after every query.
+ *
+ * If `metrics_addr` is set, the server's stats are served there over plain
+ * HTTP. This is meant for a local scraper, don't expose it publicly.
+ *
+ * With `tls` set, clients have to connect over TLS. Clients turned away for
+ * lack of capacity then just see the connection closed, rather than being
+ * told the server is busy.
+ *
+ * With an `authorizer`, clients have to present a token it accepts before
+ * their first query on every connection. Queries are then counted against
+ * `client_limit` under the identity it returns, and against `ip_limit` under
+ * the client's address, whether authenticated or not. IPv6 addresses count
+ * per /64. Rejected tokens count against `ip_limit` too, and once that's used
+ * up, tokens aren't even checked anymore.
+ *
+ * Messages from clients larger than `size_limits` allows for their type are
+ * refused, and the connection closed.
+ *
+ * Clients asking for compression get the first method they offered that is
+ * also listed in `compression`.
+ *
+ * Responses larger than `response_part_size` bytes are streamed in parts of
+ * at most that size, so that clients can follow their progress, and decode
+ * the replies to batch queries one by one as they come in. Clients refuse
+ * parts larger than their `ResponsePart` size limit, 16 MiB by default.
+ */
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub max_connections: usize,
+    pub max_pending: usize,
+    pub queue_depth: usize,
+    pub preprocess_threads: usize,
+    pub when_queue_empty: EmptyQueue,
+    pub metrics_addr: Option<SocketAddr>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    pub authorizer: Option<Authorizer>,
+    pub client_limit: Option<RateLimit>,
+    pub ip_limit: Option<RateLimit>,
+    pub size_limits: SizeLimits,
+    pub compression: Vec<Compression>,
+    pub response_part_size: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 64,
+            max_pending: 256,
+            queue_depth: 8,
+            preprocess_threads: 1,
+            when_queue_empty: EmptyQueue::Inline,
+            metrics_addr: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            authorizer: None,
+            client_limit: None,
+            ip_limit: None,
+            size_limits: SizeLimits::default(),
+            compression: Compression::supported(),
+            response_part_size: RESPONSE_PART_SIZE,
+        }
+    }
+}
+
+/**
+ * What to do when a client needs a RaidPIR seed, but none are precomputed.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyQueue {
+    /// Wait for the background threads to precompute one.
+    Block,
+    /// Do the precomputation on the connection thread.
+    Inline,
+    /// Tell the client the server is busy. Batch queries need a seed for
+    /// every chunk they touch, so ones touching more chunks than
+    /// `queue_depth` are always turned away.
+    Reject,
+}
+
+/**
+ * State shared by all threads serving clients on one address.
+ */
+#[derive(Debug)]
+pub(crate) struct ServerState {
+    pub(crate) config: ServerConfig,
+    pub(crate) stopping: AtomicBool,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) limiter: RateLimiter,
+}
+
+impl ServerState {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        Self {
+            config: config.clone(),
+            stopping: AtomicBool::new(false),
+            metrics: Arc::new(Metrics::default()),
+            limiter: RateLimiter::default(),
+        }
+    }
+
+    /**
+     * Check a client's token, returning the identity it belongs to. Rejected
+     * tokens are charged to the client's address like a query, so they can't
+     * be guessed any faster than queries are answered.
+     */
+    pub(crate) fn authorize(&self,
+        authorizer: &Authorizer,
+        peer: SocketAddr,
+        token: &[u8]
+    ) -> Result<String, HybridPirError> {
+        if !self.limiter.has_quota(peer.ip(), self.config.ip_limit) {
+            return Err(HybridPirError::RateLimited { peer: None });
+        }
+
+        match authorizer.authorize(peer, token) {
+            Some(client) => Ok(client),
+            None => {
+                self.limiter.check(peer.ip(), self.config.ip_limit, None, None, 1);
+                Err(HybridPirError::Unauthorized { peer: None })
+            },
+        }
+    }
+
+    /**
+     * Set up the TLS session on a newly accepted connection, if enabled.
+     */
+    fn stream(&self, stream: TcpStream) -> Result<Stream, HybridPirError> {
+        #[cfg(feature = "tls")]
+        {
+            if let Some(config) = self.config.tls.as_ref() {
+                let connection = rustls::ServerConnection::new(config.clone())
+                    .map_err(|e| HybridPirError::InvalidParameters(format!("{}", e)))?;
+
+                return Ok(Stream::tls_server(connection, stream));
+            }
+        }
+
+        Ok(Stream::plain(stream))
+    }
+
+    fn uses_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        {
+            self.config.tls.is_some()
+        }
+
+        #[cfg(not(feature = "tls"))]
+        {
+            false
+        }
+    }
+}
+
+/**
+ * A RaidPIR server along with how many seeds it has precomputed. The RaidPIR
+ * server doesn't tell, so every precomputation and seed has to go through
+ * here.
+ */
+#[derive(Debug)]
+struct RaidPirQueue {
+    server: RaidPirServer<RaidPirData>,
+    depth: Mutex<usize>,
+    changed: Condvar,
+    /// Replaced after an update, nobody refills it anymore.
+    retired: AtomicBool,
+}
+
+impl RaidPirQueue {
+    fn new(server: RaidPirServer<RaidPirData>) -> Self {
+        Self {
+            server,
+            depth: Mutex::new(0),
+            changed: Condvar::new(),
+            retired: AtomicBool::new(false),
+        }
+    }
+
+    fn depth(&self) -> usize {
+        *self.depth.lock().unwrap()
+    }
+
+    fn preprocess(&self) {
+        self.server.preprocess();
+
+        *self.depth.lock().unwrap() += 1;
+        self.changed.notify_all();
+    }
+
+    /**
+     * Take a seed, letting the RaidPIR server compute one on the spot if
+     * there are none left.
+     */
+    fn seed(&self) -> u128 {
+        self.reserve(self.depth.lock().unwrap(), 1);
+        self.server.seed()
+    }
+
+    /**
+     * Take `count` seeds according to the given policy, returning None if
+     * the queue doesn't hold enough and they should be refused. Refused seeds
+     * stay in the queue.
+     */
+    fn take(&self, count: usize, policy: EmptyQueue) -> Option<Vec<u128>> {
+        let mut depth = self.depth.lock().unwrap();
+
+        match policy {
+            EmptyQueue::Inline => {},
+            EmptyQueue::Reject => {
+                if *depth < count {
+                    return None;
+                }
+            },
+            EmptyQueue::Block => {
+                // One at a time, since the queue may be kept shallower than
+                // `count`
+                let mut seeds = Vec::with_capacity(count);
+
+                for _ in 0..count {
+                    while *depth == 0 && !self.retired.load(Ordering::SeqCst) {
+                        depth = self.changed.wait_timeout(depth, SHUTDOWN_POLL_INTERVAL).unwrap().0;
+                    }
+
+                    self.reserve(depth, 1);
+                    seeds.push(self.server.seed());
+                    depth = self.depth.lock().unwrap();
+                }
+
+                return Some(seeds);
+            },
+        }
+
+        self.reserve(depth, count);
+        Some((0..count).map(|_| self.server.seed()).collect())
+    }
+
+    /**
+     * Count `count` seeds as taken before taking them, so nobody else counts
+     * on them in the meantime.
+     */
+    fn reserve(&self, mut depth: MutexGuard<usize>, count: usize) {
+        *depth = depth.saturating_sub(count);
+        drop(depth);
+
+        self.changed.notify_all();
+    }
+
+    /**
+     * Wait until the queue is below the given depth, giving up after a
+     * while so the caller can check on other things.
+     */
+    fn wait_below(&self, target: usize, timeout: Duration) -> bool {
+        let depth = self.depth.lock().unwrap();
+
+        if *depth < target {
+            return true;
+        }
+
+        let depth = self.changed.wait_timeout(depth, timeout).unwrap().0;
+        *depth < target
+    }
+
+    fn retire(&self) {
+        self.retired.store(true, Ordering::SeqCst);
+        self.changed.notify_all();
+    }
+}
+
+/// How long a connection may sit idle in between queries.
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often idle connections check whether the server is shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Idle SealPIR servers kept around for reuse.
+const SEALPIR_CACHE_SIZE: usize = 16;
+
+/**
+ * A SealPIR server kept around between queries, so the encryption context
+ * and a client's expanded Galois key don't have to be set up again every
+ * time. Only the database changes from query to query.
+ */
+struct SealPirContext {
+    server: PirServer<'static>,
+}
+
+// SealPIR servers only hold on to state of their own, and a context is only
+// ever used by the one thread that took it out of the cache.
+unsafe impl Send for SealPirContext {}
+
+/// Chunk size, element size and SealPIR parameters.
+type SealPirShape = (u32, u32, u32, u32, u32);
+
+/**
+ * SealPIR servers not answering a query right now, each set up for one
+ * session, least recently used first. Queries take a server out and put it
+ * back when done, so each is only ever used by one thread at a time. A query
+ * that panics never puts its server back, wherever it ran, so none are left
+ * half set up for the next one.
+ */
+#[derive(Default)]
+struct SealPirCache {
+    idle: Mutex<VecDeque<((SealPirShape, u64), SealPirContext)>>,
+}
+
+impl SealPirCache {
+    /**
+     * Take a server of the given shape with the session's key set. Once the
+     * cache is full, the least recently used server gets the key set
+     * instead, and only with nothing to reuse, a new server is set up.
+     */
+    fn take(&self, shape: SealPirShape, session: u64, sealpir_key: &Vec<u8>) -> SealPirContext {
+        let reused = {
+            let mut idle = self.idle.lock().unwrap();
+
+            if let Some(i) = idle.iter().rposition(|(key, _)| *key == (shape, session)) {
+                return idle.remove(i).unwrap().1;
+            }
+
+            if idle.len() >= SEALPIR_CACHE_SIZE {
+                idle.iter()
+                    .position(|((s, _), _)| *s == shape)
+                    .and_then(|i| idle.remove(i))
+                    .map(|(_, context)| context)
+            } else {
+                None
+            }
+        };
+
+        let mut context = reused.unwrap_or_else(|| {
+            debug!("Setting up SealPIR server...");
+
+            SealPirContext {
+                server: PirServer::new(shape.0, shape.1, shape.2, shape.3, shape.4),
+            }
+        });
+
+        context.server.set_galois_key(sealpir_key, 0);
+        context
+    }
+
+    /**
+     * Put a server back once its query is answered, dropping the least
+     * recently used one if that makes too many.
+     */
+    fn put(&self, shape: SealPirShape, session: u64, context: SealPirContext) {
+        let mut idle = self.idle.lock().unwrap();
+
+        idle.push_back(((shape, session), context));
+        if idle.len() > SEALPIR_CACHE_SIZE {
+            idle.pop_front();
+        }
+    }
+}
+
+impl fmt::Debug for SealPirCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SealPirCache({} idle)", self.idle.lock().unwrap().len())
+    }
+}
+
+/**
+ * SealPIR Galois keys registered by clients, indexed by session ID. Once full,
+ * the oldest registration is evicted, and that client simply has to register
+ * its key again.
+ */
+#[derive(Debug, Default)]
+struct KeyCache {
+    keys: HashMap<u64, Arc<Vec<u8>>>,
+    order: VecDeque<u64>,
+}
+
+impl KeyCache {
+    fn get(&self, session: u64) -> Option<Arc<Vec<u8>>> {
+        self.keys.get(&session).cloned()
+    }
+
+    /**
+     * Cache a key, unless the session already has one. That's only reused if
+     * it's the same key, a session can't be taken over with another one.
+     */
+    fn insert(&mut self, session: u64, key: Vec<u8>) -> Result<Arc<Vec<u8>>, HybridPirError> {
+        if let Some(existing) = self.keys.get(&session) {
+            if **existing != key {
+                return Err(HybridPirError::protocol("Session already has a different key."));
+            }
+
+            return Ok(existing.clone());
+        }
+
+        while self.order.len() >= MAX_CACHED_KEYS {
+            if let Some(evicted) = self.order.pop_front() {
+                self.keys.remove(&evicted);
+            }
+        }
+
+        let key = Arc::new(key);
+        self.keys.insert(session, key.clone());
+        self.order.push_back(session);
+        Ok(key)
+    }
+}
+
+/**
+ * Streams database records straight into RaidPIR chunks, so a server can be
+ * set up without holding the database in memory as individual elements.
+ *
+ * The chunks are handed to RaidPIR as they are, so the database only has to
+ * fit into memory once. Servers that should take updates later on have to
+ * opt in with `updatable`, which keeps a copy of every chunk to apply them
+ * to.
+ *
+ * ```
+ * use hybridpir::params::HybridPirParams;
+ * use hybridpir::server::HybridPirServer;
+ *
+ * let params = HybridPirParams::builder(1 << 12, 8).raidpir_size(1 << 8).build().unwrap();
+ *
+ * let mut builder = HybridPirServer::builder(0, &params).unwrap();
+ * builder.extend((0..1u64 << 12).map(|i| i.to_le_bytes())).unwrap();
+ * let server = builder.build().unwrap();
+ *
+ * assert!(server.update(0, vec![0; 8]).is_err());
+ * ```
+ */
+#[derive(Debug)]
+pub struct HybridPirServerBuilder {
+    params: HybridPirParams,
+    raidpir_id: usize,
+    updatable: bool,
+    chunks: Vec<Vec<u8>>,
+    records: usize,
+}
+
+impl HybridPirServerBuilder {
+    pub fn new(raidpir_id: usize, params: &HybridPirParams) -> Result<Self, HybridPirError> {
+        params.validate()?;
+
+        if raidpir_id >= params.raidpir_servers {
+            return Err(HybridPirError::InvalidParameters(
+                format!("Server ID must be below {}.", params.raidpir_servers)));
+        }
+
+        let num_chunks = (params.db_len + params.raidpir_chunksize() - 1) / params.raidpir_chunksize();
+
+        Ok(Self {
+            params: params.clone(),
+            raidpir_id,
+            updatable: false,
+            chunks: Vec::with_capacity(num_chunks),
+            records: 0,
+        })
+    }
+
+    /**
+     * Whether to keep a copy of the database to support `update` and
+     * `apply_batch`, doubling memory usage. Off by default.
+     */
+    pub fn updatable(mut self, updatable: bool) -> Self {
+        self.updatable = updatable;
+        self
+    }
+
+    /**
+     * Make room for up to `n` more records in the current chunk, returning
+     * how many fit.
+     */
+    fn reserve(&mut self, n: usize) -> usize {
+        let raidpir_chunksize = self.params.raidpir_chunksize();
+
+        if self.chunks.len() * raidpir_chunksize <= self.records {
+            let elements = raidpir_chunksize.min(self.params.db_len - self.records);
+            self.chunks.push(Vec::with_capacity(elements * self.params.element_size));
+        }
+
+        n.min(raidpir_chunksize - self.records % raidpir_chunksize)
+    }
+
+    fn check_remaining(&self, n: usize) -> Result<(), HybridPirError> {
+        if self.records + n > self.params.db_len {
+            return Err(HybridPirError::InvalidParameters(
+                format!("Expected {} elements, got more.", self.params.db_len)));
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Append a record, padding it and adding a length header if records have
+     * variable length.
+     */
+    pub fn push(&mut self, record: &[u8]) -> Result<(), HybridPirError> {
+        self.check_remaining(1)?;
+
+        self.reserve(1);
+        self.params.encode_record(self.records, record, self.chunks.last_mut().unwrap())?;
+        self.records += 1;
+
+        Ok(())
+    }
+
+    pub fn extend<I, T>(&mut self, records: I) -> Result<(), HybridPirError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        for record in records {
+            self.push(record.as_ref())?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Read all remaining elements from `reader`, one chunk at a time. These
+     * are taken as they are, so with variable length records, they already
+     * have to include length header and padding.
+     */
+    pub fn read_from<R: Read>(&mut self, mut reader: R) -> Result<(), HybridPirError> {
+        let t = Instant::now();
+
+        while self.records < self.params.db_len {
+            let n = self.reserve(self.params.db_len - self.records);
+
+            let chunk = self.chunks.last_mut().unwrap();
+            let start = chunk.len();
+            chunk.resize(start + n * self.params.element_size, 0);
+            reader.read_exact(&mut chunk[start..])?;
+
+            self.records += n;
+        }
+
+        debug!("Read {} chunks ({:.4}ms).",
+            self.chunks.len(),
+            t.elapsed().as_secs_f64() * 1000.0);
+
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<HybridPirServer, HybridPirError> {
+        if self.records != self.params.db_len {
+            return Err(HybridPirError::InvalidParameters(
+                format!("Expected {} elements, got {}.", self.params.db_len, self.records)));
+        }
+
+        let (raidpir, chunks) = if self.updatable {
+            let raidpir = HybridPirServer::raidpir_server(self.chunks.clone(), self.raidpir_id, &self.params);
+            (raidpir, Some(Arc::new(Mutex::new(self.chunks))))
+        } else {
+            (HybridPirServer::raidpir_server(self.chunks, self.raidpir_id, &self.params), None)
+        };
+
+        Ok(HybridPirServer {
+            fingerprint: self.params.fingerprint(),
+            raidpir_query_bits: HybridPirServer::raidpir_query_bits(self.raidpir_id, &self.params),
+            params: self.params,
+            raidpir_id: self.raidpir_id,
+            chunks,
+            raidpir: Arc::new(RwLock::new(Arc::new(RaidPirQueue::new(raidpir)))),
+            sealpir_keys: Arc::new(RwLock::new(KeyCache::default())),
+            sealpir_servers: Arc::new(SealPirCache::default()),
+        })
+    }
+}
+
+/**
+ * A single HybridPIR server. Clones share the database, key cache and cached
+ * SealPIR servers, so the same server can be handed to many connections.
+ *
+ * The RaidPIR server can't be modified once set up, so servers built with
+ * `HybridPirServerBuilder::updatable` keep a copy of the database chunks
+ * around to apply updates to, and swap in a new RaidPIR server afterwards.
+ */
 #[derive(Debug, Clone)]
 pub struct HybridPirServer {
-    db_len: usize,
-    element_size: usize,
-    raidpir: Arc<RaidPirServer<RaidPirData>>,
-    raidpir_size: usize,
-    sealpir_poly_degree: u32,
-    sealpir_log: u32,
-    sealpir_d: u32,
+    params: HybridPirParams,
+    fingerprint: ParamsFingerprint,
+    raidpir_id: usize,
+    raidpir_query_bits: usize,
+    chunks: Option<Arc<Mutex<Vec<Vec<u8>>>>>,
+    raidpir: Arc<RwLock<Arc<RaidPirQueue>>>,
+    sealpir_keys: Arc<RwLock<KeyCache>>,
+    sealpir_servers: Arc<SealPirCache>,
 }
 
 impl HybridPirServer {
     pub fn new(
         db: &Vec<Vec<u8>>,
         raidpir_id: usize,
-        raidpir_servers: usize,
-        raidpir_redundancy: usize,
-        raidpir_size: usize,
-        raidpir_russians: bool,
-        sealpir_poly_degree: u32,
-        sealpir_log: u32,
-        sealpir_d: u32,
-    ) -> Self {
-        assert!(db.len() > 0);
-        assert!(raidpir_size < db.len());
-        assert!(raidpir_size % (raidpir_servers * 8) == 0);
-
-        let raidpir_chunksize = (db.len() as f32 / raidpir_size as f32).ceil() as usize;
-
-        let raidpir_db: Vec<RaidPirData> = db
-            .chunks(raidpir_chunksize)
-            .map(|x| RaidPirData::new(x.into_iter().cloned().flatten().collect::<Vec<u8>>()))
+        params: &HybridPirParams,
+    ) -> Result<Self, HybridPirError> {
+        let mut builder = Self::builder(raidpir_id, params)?;
+        builder.extend(db)?;
+        builder.build()
+    }
+
+    pub fn builder(raidpir_id: usize, params: &HybridPirParams) -> Result<HybridPirServerBuilder, HybridPirError> {
+        HybridPirServerBuilder::new(raidpir_id, params)
+    }
+
+    /**
+     * Set up a server from a file of `params.db_len` records, each
+     * `params.element_size` bytes long, without reading it into memory as
+     * individual elements first. With the `mmap` feature, the file is
+     * memory-mapped instead of read.
+     */
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        raidpir_id: usize,
+        params: &HybridPirParams,
+    ) -> Result<Self, HybridPirError> {
+        let file = File::open(path)?;
+
+        let expected = (params.db_len * params.element_size) as u64;
+        let actual = file.metadata()?.len();
+        if actual != expected {
+            return Err(HybridPirError::InvalidParameters(
+                format!("Expected a file of {} bytes, got {}.", expected, actual)));
+        }
+
+        #[cfg(feature = "mmap")]
+        {
+            // Safe as long as nobody truncates the file while we copy it
+            let mmap = unsafe { memmap2::Mmap::map(&file)? };
+            Self::from_reader(&mmap[..], raidpir_id, params)
+        }
+
+        #[cfg(not(feature = "mmap"))]
+        {
+            Self::from_reader(BufReader::new(file), raidpir_id, params)
+        }
+    }
+
+    /**
+     * Set up a server from `params.db_len` records of `params.element_size`
+     * bytes each, read one RaidPIR chunk at a time.
+     */
+    pub fn from_reader<R: Read>(
+        reader: R,
+        raidpir_id: usize,
+        params: &HybridPirParams,
+    ) -> Result<Self, HybridPirError> {
+        let mut builder = Self::builder(raidpir_id, params)?;
+        builder.read_from(reader)?;
+        builder.build()
+    }
+
+    fn raidpir_server(
+        chunks: Vec<Vec<u8>>,
+        raidpir_id: usize,
+        params: &HybridPirParams,
+    ) -> RaidPirServer<RaidPirData> {
+        let raidpir_db: Vec<RaidPirData> = chunks
+            .into_iter()
+            .map(|x| RaidPirData::new(x))
             .collect();
 
-        let raidpir = RaidPirServer::new(
+        RaidPirServer::new(
             raidpir_db,
             raidpir_id,
-            raidpir_servers,
-            raidpir_redundancy,
-            raidpir_russians);
+            params.raidpir_servers,
+            params.raidpir_redundancy,
+            params.raidpir_russians)
+    }
 
+    /**
+     * Length of the RaidPIR queries this server gets. RaidPIR doesn't say,
+     * so build a query once to find out.
+     */
+    fn raidpir_query_bits(raidpir_id: usize, params: &HybridPirParams) -> usize {
+        let client = RaidPirClient::new(params.raidpir_size, params.raidpir_servers, params.raidpir_redundancy);
+
+        client.query(0, &vec![0; params.raidpir_servers])[raidpir_id].len()
+    }
+
+    fn raidpir(&self) -> Arc<RaidPirQueue> {
+        self.raidpir.read().unwrap().clone()
+    }
+
+    /**
+     * A copy of this server pinned to the current state of the database, so
+     * a query cycle can't be affected by updates applied in the meantime.
+     */
+    pub(crate) fn snapshot(&self) -> Self {
         Self {
-            db_len: db.len(),
-            element_size: db[0].len(),
-            raidpir: Arc::new(raidpir),
-            raidpir_size,
-            sealpir_poly_degree,
-            sealpir_log,
-            sealpir_d,
+            raidpir: Arc::new(RwLock::new(self.raidpir())),
+            ..self.clone()
+        }
+    }
+
+    /**
+     * Replace a single element of the database, see `apply_batch`.
+     */
+    pub fn update(&self, index: usize, value: Vec<u8>) -> Result<(), HybridPirError> {
+        self.apply_batch(vec![(index, value)])
+    }
+
+    /**
+     * Replace several elements of the database at once.
+     *
+     * Only the affected chunks are patched, and nothing is rebuilt if none of
+     * them actually changed. Otherwise the RaidPIR server has to be set up
+     * again, which takes a copy of the whole database since it owns its
+     * chunks, so updates should be batched where possible. Its queue is
+     * refilled before it's swapped in, queries already in progress are
+     * answered from the old database.
+     */
+    pub fn apply_batch(&self, updates: Vec<(usize, Vec<u8>)>) -> Result<(), HybridPirError> {
+        let chunks = self.chunks.as_ref().ok_or(HybridPirError::InvalidParameters(
+            "Server was set up without support for updates.".into()))?;
+
+        let mut elements: Vec<(usize, Vec<u8>)> = Vec::with_capacity(updates.len());
+        for (index, value) in updates.into_iter() {
+            if index >= self.params.db_len {
+                return Err(HybridPirError::IndexOutOfRange {
+                    index,
+                    db_len: self.params.db_len,
+                });
+            }
+
+            let mut element = Vec::with_capacity(self.params.element_size);
+            self.params.encode_record(index, &value, &mut element)?;
+            elements.push((index, element));
+        }
+
+        if elements.is_empty() {
+            return Ok(());
+        }
+
+        let t = Instant::now();
+
+        // Held until the new server is in place, so concurrent updates can't
+        // overwrite each other.
+        let mut chunks = chunks.lock().unwrap();
+
+        let raidpir_chunksize = self.params.raidpir_chunksize();
+        let mut touched: Vec<usize> = Vec::new();
+
+        for (index, element) in elements.iter() {
+            let chunk = index / raidpir_chunksize;
+            let offset = (index % raidpir_chunksize) * self.params.element_size;
+            let target = &mut chunks[chunk][offset..offset + element.len()];
+
+            if target != &element[..] {
+                target.copy_from_slice(element);
+                touched.push(chunk);
+            }
+        }
+
+        if touched.is_empty() {
+            debug!("Skipped {} updates, nothing changed.", elements.len());
+            return Ok(());
+        }
+
+        touched.sort_unstable();
+        touched.dedup();
+
+        let raidpir = RaidPirQueue::new(Self::raidpir_server(chunks.clone(), self.raidpir_id, &self.params));
+        raidpir.preprocess();
+
+        let old = std::mem::replace(&mut *self.raidpir.write().unwrap(), Arc::new(raidpir));
+        old.retire();
+
+        debug!("Applied {} updates to {} chunks ({:.4}ms).",
+            elements.len(),
+            touched.len(),
+            t.elapsed().as_secs_f64() * 1000.0);
+
+        Ok(())
+    }
+
+    pub fn params(&self) -> &HybridPirParams {
+        &self.params
+    }
+
+    pub fn fingerprint(&self) -> ParamsFingerprint {
+        self.fingerprint
+    }
+
+    /**
+     * Make sure a client was set up with the same parameters as we were,
+     * otherwise all it would get out of our responses is garbage.
+     */
+    pub fn check_params(&self, remote: &ParamsFingerprint) -> Result<(), HybridPirError> {
+        if *remote != self.fingerprint {
+            return Err(HybridPirError::ParamsMismatch {
+                peer: None,
+                local: self.fingerprint,
+                remote: *remote,
+            });
         }
+
+        Ok(())
+    }
+
+    /**
+     * Store a client's SealPIR Galois key, returning the session ID it can be
+     * retrieved with.
+     */
+    pub fn register_key(&self, sealpir_key: Vec<u8>) -> Result<u64, HybridPirError> {
+        let session = session_id(&sealpir_key);
+        self.sealpir_keys.write().unwrap().insert(session, sealpir_key)?;
+        Ok(session)
     }
 
+    /**
+     * Register a key received for the given session, failing if it doesn't
+     * actually belong to it. Returns the key as cached, which stays valid for
+     * the caller even if it's evicted from the cache in the meantime.
+     */
+    pub fn register_session_key(&self, session: u64, sealpir_key: Vec<u8>) -> Result<Arc<Vec<u8>>, HybridPirError> {
+        if session_id(&sealpir_key) != session {
+            return Err(HybridPirError::protocol("Key does not match session."));
+        }
+
+        self.sealpir_keys.write().unwrap().insert(session, sealpir_key)
+    }
+
+    pub fn sealpir_key(&self, session: u64) -> Option<Arc<Vec<u8>>> {
+        self.sealpir_keys.read().unwrap().get(session)
+    }
+
+    /**
+     * Precompute one more RaidPIR seed.
+     */
     pub fn preprocess(&self) {
-        self.raidpir.preprocess();
+        self.raidpir().preprocess();
     }
 
+    /**
+     * Number of RaidPIR seeds currently precomputed.
+     */
+    pub fn queue_depth(&self) -> usize {
+        self.raidpir().depth()
+    }
+
+    /**
+     * Take a seed from the RaidPIR queue, or compute one if it's empty. The
+     * response to it has to be calculated before any updates are applied.
+     */
     pub fn seed(&self) -> u128 {
-        self.raidpir.seed()
+        self.raidpir().seed()
     }
 
-    pub fn response(&self,
+    /**
+     * Keep the RaidPIR queue filled up to `queue_depth`, until the server
+     * shuts down. Picks up the new queue after updates.
+     */
+    pub(crate) fn refill(&self, state: &ServerState) {
+        while !state.stopping.load(Ordering::SeqCst) {
+            let raidpir = self.raidpir();
+
+            if raidpir.wait_below(state.config.queue_depth, SHUTDOWN_POLL_INTERVAL) {
+                raidpir.preprocess();
+            }
+        }
+    }
+
+    /**
+     * Combine the RaidPIR part of a query into a single chunk of the database,
+     * and run `f` on a cached SealPIR server, set up with that chunk and the
+     * key of the given session.
+     */
+    fn with_sealpir<R, F: FnOnce(&PirServer) -> R>(&self,
         seed: u128,
         raidpir_query: &BitVec<Lsb0, u8>,
+        session: u64,
         sealpir_key: &Vec<u8>,
-        sealpir_query: &PirQuery
-    ) -> PirReply {
-        let mut response: Vec<u8> = self.raidpir
+        f: F
+    ) -> R {
+        let mut response: Vec<u8> = self.raidpir()
+            .server
             .response(seed, &raidpir_query)
             .into();
 
-        let raidpir_chunksize = (self.db_len as f32 / self.raidpir_size as f32).ceil() as usize;
+        let raidpir_chunksize = self.params.raidpir_chunksize();
 
         // resize response so every element is full-size
-        response.resize(raidpir_chunksize * self.element_size, 0);
+        response.resize(raidpir_chunksize * self.params.element_size, 0);
 
-        let mut sealpir = PirServer::new(
+        let shape = (
             raidpir_chunksize as u32,
-            self.element_size as u32,
-            self.sealpir_poly_degree,
-            self.sealpir_log,
-            self.sealpir_d
+            self.params.element_size as u32,
+            self.params.sealpir_poly_degree,
+            self.params.sealpir_log,
+            self.params.sealpir_d
         );
 
-        sealpir.set_galois_key(sealpir_key, 0);
-        sealpir.setup(response);
+        let mut context = self.sealpir_servers.take(shape, session, sealpir_key);
+        context.server.setup(response);
+
+        let result = f(&context.server);
+        self.sealpir_servers.put(shape, session, context);
 
-        sealpir.gen_reply(sealpir_query, 0)
+        result
     }
 
-    pub fn accept_connections<A: ToSocketAddrs>(self, addr: A) -> Result<(), Error> {
+    pub fn response(&self,
+        seed: u128,
+        raidpir_query: &BitVec<Lsb0, u8>,
+        sealpir_key: &Vec<u8>,
+        sealpir_query: &PirQuery
+    ) -> PirReply {
+        self.session_response(seed, raidpir_query, session_id(sealpir_key), sealpir_key, sealpir_query)
+    }
+
+    /**
+     * Like `response`, for a key whose session ID is already known, which
+     * saves hashing it again.
+     */
+    pub(crate) fn session_response(&self,
+        seed: u128,
+        raidpir_query: &BitVec<Lsb0, u8>,
+        session: u64,
+        sealpir_key: &Vec<u8>,
+        sealpir_query: &PirQuery
+    ) -> PirReply {
+        self.with_sealpir(seed, raidpir_query, session, sealpir_key, |sealpir| {
+            sealpir.gen_reply(sealpir_query, 0)
+        })
+    }
+
+    /**
+     * Answer a batch query, consisting of one RaidPIR query per seed and any
+     * number of SealPIR queries for the chunk selected by each of them.
+     */
+    pub fn response_batch(&self,
+        seeds: &[u128],
+        raidpir_queries: &[BitVec<Lsb0, u8>],
+        sealpir_key: &Vec<u8>,
+        sealpir_queries: &[Vec<PirQuery>]
+    ) -> Result<Vec<Vec<PirReply>>, HybridPirError> {
+        self.session_response_batch(seeds, raidpir_queries, session_id(sealpir_key), sealpir_key, sealpir_queries)
+    }
+
+    /**
+     * Like `response_batch`, for a key whose session ID is already known.
+     */
+    pub(crate) fn session_response_batch(&self,
+        seeds: &[u128],
+        raidpir_queries: &[BitVec<Lsb0, u8>],
+        session: u64,
+        sealpir_key: &Vec<u8>,
+        sealpir_queries: &[Vec<PirQuery>]
+    ) -> Result<Vec<Vec<PirReply>>, HybridPirError> {
+        if seeds.len() != raidpir_queries.len() || seeds.len() != sealpir_queries.len() {
+            return Err(HybridPirError::InvalidParameters(
+                "Need one RaidPIR query and set of SealPIR queries per seed.".into()));
+        }
+
+        Ok(seeds
+            .par_iter()
+            .zip(raidpir_queries.par_iter().zip(sealpir_queries.par_iter()))
+            .map(|(seed, (raidpir_query, sealpir_queries))| {
+                self.with_sealpir(*seed, raidpir_query, session, sealpir_key, |sealpir| {
+                    sealpir_queries
+                        .iter()
+                        .map(|sealpir_query| sealpir.gen_reply(sealpir_query, 0))
+                        .collect()
+                })
+            })
+            .collect())
+    }
+
+    /**
+     * Make sure a query has the shape RaidPIR and SealPIR expect for these
+     * parameters before handing it to them. Neither checks, and malformed
+     * queries make them panic or worse.
+     */
+    pub(crate) fn check_query(&self,
+        raidpir_query: &BitVec<Lsb0, u8>,
+        sealpir_queries: &[PirQuery]
+    ) -> Result<(), HybridPirError> {
+        if raidpir_query.len() != self.raidpir_query_bits {
+            return Err(HybridPirError::protocol(format!(
+                "RaidPIR query has {} bits, expected {}.", raidpir_query.len(), self.raidpir_query_bits)));
+        }
+
+        // At most one ciphertext per poly_degree elements in every dimension
+        let poly_degree = self.params.sealpir_poly_degree as usize;
+        let max_ciphertexts = self.params.sealpir_d as usize
+            * ((self.params.raidpir_chunksize() + poly_degree - 1) / poly_degree);
+
+        for sealpir_query in sealpir_queries {
+            let num = sealpir_query.num as usize;
+
+            if num == 0 || num > max_ciphertexts || sealpir_query.query.is_empty() {
+                return Err(HybridPirError::protocol(format!(
+                    "SealPIR query has {} ciphertexts in {} bytes.", num, sealpir_query.query.len())));
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Start serving clients on the given address in the background. Binding
+     * to port 0 picks a free port, which `ServerHandle::local_addr` reports.
+     */
+    pub fn accept_connections<A: ToSocketAddrs>(self, addr: A) -> Result<ServerHandle, HybridPirError> {
+        self.accept_connections_with(addr, &ServerConfig::default())
+    }
+
+    /**
+     * Start serving clients on the given address with a fixed pool of worker
+     * threads, turning clients away once the limits in `config` are reached.
+     */
+    pub fn accept_connections_with<A: ToSocketAddrs>(self,
+        addr: A,
+        config: &ServerConfig
+    ) -> Result<ServerHandle, HybridPirError> {
+        if config.max_connections == 0 {
+            return Err(HybridPirError::InvalidParameters("At least one connection must be allowed.".into()));
+        }
+
         let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let state = Arc::new(ServerState::new(config));
 
-        debug!("Listening on {:?}...", listener.local_addr().unwrap());
+        // With max_pending at zero, connections only go to idle workers
+        let (sender, receiver) = mpsc::sync_channel::<TcpStream>(config.max_pending);
+        let receiver = Arc::new(Mutex::new(receiver));
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let x = self.clone();
-                    std::thread::spawn(move || {
-                        if let Err(e) = x.handle_connection(stream) {
-                            error!("{:?}", e);
+        let mut threads = Vec::with_capacity(config.max_connections + config.preprocess_threads + 2);
+
+        let metrics_addr = match config.metrics_addr {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                let metrics_addr = listener.local_addr()?;
+                let server = self.clone();
+                let state = state.clone();
+
+                debug!("Serving metrics on {:?}...", metrics_addr);
+
+                threads.push(std::thread::Builder::new()
+                    .name("hybridpir-metrics".into())
+                    .spawn(move || server.serve_metrics(listener, &state))?);
+
+                Some(metrics_addr)
+            },
+            None => None,
+        };
+
+        if config.queue_depth > 0 {
+            for i in 0..config.preprocess_threads {
+                let server = self.clone();
+                let state = state.clone();
+
+                threads.push(std::thread::Builder::new()
+                    .name(format!("hybridpir-preprocess-{}", i))
+                    .spawn(move || server.refill(&state))?);
+            }
+        }
+
+        for i in 0..config.max_connections {
+            let server = self.clone();
+            let receiver = receiver.clone();
+            let state = state.clone();
+
+            threads.push(std::thread::Builder::new()
+                .name(format!("hybridpir-worker-{}", i))
+                .spawn(move || server.work(&receiver, &state))?);
+        }
+
+        debug!("Listening on {:?} with {} workers...", local_addr, config.max_connections);
+
+        let acceptor_state = state.clone();
+        threads.push(std::thread::Builder::new()
+            .name("hybridpir-acceptor".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if acceptor_state.stopping.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    match stream {
+                        Ok(stream) => {
+                            match sender.try_send(stream) {
+                                Ok(()) => {},
+                                Err(TrySendError::Full(stream))
+                                | Err(TrySendError::Disconnected(stream)) => {
+                                    Self::reject(stream, &acceptor_state)
+                                },
+                            }
+                        },
+                        Err(e) => {
+                            error!("{}", e);
                         }
-                    });
+                    }
+                }
+
+                debug!("Stopped listening on {:?}.", local_addr);
+
+                // Dropping the sender lets workers run out of connections
+            })?);
+
+        Ok(ServerHandle {
+            server: self,
+            local_addr,
+            metrics_addr,
+            state,
+            threads: Mutex::new(threads),
+        })
+    }
+
+    /**
+     * Worker thread handling one queued connection after another, until the
+     * server shuts down.
+     */
+    fn work(&self, receiver: &Mutex<Receiver<TcpStream>>, state: &ServerState) {
+        loop {
+            // Only hold the lock while waiting, not while serving
+            let stream = match receiver.lock().unwrap().recv() {
+                Ok(stream) => stream,
+                Err(_) => break,
+            };
+
+            // Connections still queued when shutting down never get served
+            if state.stopping.load(Ordering::SeqCst) {
+                Self::reject(stream, state);
+                continue;
+            }
+
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(e) => {
+                    error!("{}", e);
+                    continue;
+                }
+            };
+
+            state.metrics.connection_opened();
+
+            // A panic only takes down this connection, not the worker
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                state.stream(stream).and_then(|stream| self.serve(stream, state))
+            }));
+
+            match result {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => {
+                    state.metrics.error(&e);
+                    error!("{}", e.with_peer(peer));
+                },
+                Err(_) => {
+                    error!("[{:?}] Panicked while serving connection.", peer);
                 },
+            }
+
+            state.metrics.connection_closed();
+        }
+    }
+
+    /**
+     * Tell a client we can't take any more connections right now. This
+     * happens on the accepting thread, so don't wait around for a slow
+     * client.
+     */
+    fn reject(stream: TcpStream, state: &ServerState) {
+        let peer = stream.peer_addr().ok();
+
+        warn!("[{:?}] Too many connections, rejecting.", peer);
+
+        state.metrics.connection_rejected();
+
+        // Nothing was read yet, so this goes out in the default encoding
+        let mut stream = Stream::plain(stream);
+
+        // TLS clients would expect a handshake first, not worth it here
+        if !state.uses_tls() {
+            stream.tcp().set_write_timeout(Some(Duration::from_millis(100))).ok();
+            let reply = HybridPirMessage::Error {
+                code: ErrorCode::Overloaded,
+                message: "Too many connections.".to_string(),
+            };
+
+            state.metrics.send(&mut stream, &reply).ok();
+        }
+
+        stream.tcp().shutdown(std::net::Shutdown::Write).ok();
+    }
+
+    /**
+     * Answer every request on the metrics listener with the current stats,
+     * until the server shuts down.
+     */
+    pub(crate) fn serve_metrics(&self, listener: TcpListener, state: &ServerState) {
+        for stream in listener.incoming() {
+            if state.stopping.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let mut stream = match stream {
+                Ok(stream) => stream,
                 Err(e) => {
-                    error!("{:?}", e);
+                    error!("{}", e);
+                    continue;
                 }
+            };
+
+            // Whatever was requested, it gets the stats
+            stream.set_read_timeout(Some(Duration::from_secs(1))).ok();
+            stream.set_write_timeout(Some(Duration::from_secs(1))).ok();
+            let _ = stream.read(&mut [0; 1024]);
+
+            let body = state.metrics.stats(self.queue_depth()).to_string();
+            let response = format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body);
+
+            if let Err(e) = stream.write_all(response.as_bytes()) {
+                debug!("Could not send metrics: {}", e);
             }
         }
+    }
 
-        Ok(())
+    /**
+     * Serve a single client on the current thread, refilling the RaidPIR
+     * queue after every query.
+     */
+    pub fn handle_connection(&self, stream: TcpStream) -> Result<(), HybridPirError> {
+        let peer = stream.peer_addr()?;
+
+        let state = ServerState::new(&ServerConfig {
+            queue_depth: 0,
+            ..ServerConfig::default()
+        });
+
+        self.serve(Stream::plain(stream), &state).map_err(|e| e.with_peer(peer))
+    }
+
+    fn serve(&self, mut stream: Stream, state: &ServerState) -> Result<(), HybridPirError> {
+        stream.tcp().set_read_timeout(Some(IDLE_TIMEOUT))?;
+        stream.tcp().set_write_timeout(Some(IDLE_TIMEOUT))?;
+
+        let peer = stream.peer_addr()?;
+
+        debug!("[{:?}] Accepting connection, waiting for hello...", peer);
+
+        let result = self.serve_cycles(&mut stream, peer, state);
+
+        match result {
+            Ok(queries) => debug!("[{:?}] Closing connection after {} queries.", peer, queries),
+            Err(ref e) => {
+                if let Some(reply) = self.error_reply(e) {
+                    state.metrics.send(&mut stream, &reply).ok();
+                }
+            },
+        }
+
+        stream.shutdown();
+
+        result.map(|_| ())
+    }
+
+    /**
+     * Answer queries until the client closes the connection or the server
+     * shuts down, returning how many there were.
+     */
+    fn serve_cycles(&self,
+        mut stream: &mut Stream,
+        peer: SocketAddr,
+        state: &ServerState
+    ) -> Result<usize, HybridPirError> {
+        let mut queries = 0;
+
+        // Set on the first hello, if the server wants authentication
+        let mut authenticated = false;
+        let mut client = None;
+
+        // Each hello starts a new seed/query/response cycle, until the client
+        // closes the connection or the server shuts down.
+        loop {
+            if !Self::await_hello(stream.tcp(), &state.stopping)? {
+                break;
+            }
+
+            let t0 = Instant::now();
+
+            let (session, params, batch, offered) = match state.metrics.receive(&mut stream, &state.config.size_limits) {
+                Ok(HybridPirMessage::Hello(session, params, offered)) => (session, params, None, offered),
+                Ok(HybridPirMessage::BatchHello(session, params, count, offered)) => (session, params, Some(count as usize), offered),
+                Ok(HybridPirMessage::Close) => break,
+                Ok(m) => {
+                    return Err(HybridPirError::unexpected(&m));
+                },
+                // Client went away or idled out in between queries
+                Err(ref e) if e.is_disconnect() => break,
+                Err(e) => {
+                    return Err(e);
+                }
+            };
+
+            state.metrics.record(Phase::Hello, t0.elapsed());
+
+            debug!("[{:?}] Received hello ({:.4}ms).",
+                peer,
+                t0.elapsed().as_secs_f64() * 1000.0);
+
+            self.check_params(&params)?;
+
+            // Clients not asking for compression don't need to be told
+            let compression = if offered.is_empty() {
+                Compression::None
+            } else {
+                let compression = Compression::negotiate(&offered, &state.config.compression);
+                state.metrics.send(&mut stream, &HybridPirMessage::UseCompression(compression))?;
+                compression
+            };
+
+            if !authenticated {
+                client = self.authenticate(&mut stream, peer, state)?;
+                authenticated = true;
+            }
+
+            let cost = batch.unwrap_or(1);
+            if !state.limiter.check(peer.ip(), state.config.ip_limit, client.as_deref(), state.config.client_limit, cost) {
+                warn!("[{:?}] Query quota of {:?} exceeded, rejecting query.", peer, client);
+                return Err(HybridPirError::RateLimited { peer: None });
+            }
+
+            let sealpir_key = self.session_key(&mut stream, peer, session, state)?;
+
+            // Answer the whole cycle from the database as it is right now
+            let server = self.snapshot();
+
+            match batch {
+                None => server.handle_query(&mut stream, peer, session, &sealpir_key, compression, state)?,
+                Some(count) => server.handle_batch_query(&mut stream, peer, session, &sealpir_key, count, compression, state)?,
+            }
+
+            debug!("[{:?}] Total elapsed: {:.4}ms",
+                peer,
+                t0.elapsed().as_secs_f64() * 1000.0);
+
+            queries += 1;
+
+            // Without background preprocessing, use this thread to rebuild
+            // the RaidPIR queue while the client is busy decoding.
+            if state.config.queue_depth == 0 {
+                self.preprocess();
+            }
+        }
+
+        Ok(queries)
+    }
+
+    /**
+     * What to tell a client before hanging up on it because of `error`, if
+     * it can still be told anything.
+     */
+    pub(crate) fn error_reply(&self, error: &HybridPirError) -> Option<HybridPirMessage> {
+        match (error, error.code()) {
+            // The client reports both fingerprints, so send ours along
+            (HybridPirError::ParamsMismatch { .. }, _) => Some(HybridPirMessage::ParamsMismatch(self.fingerprint)),
+            (_, Some(code)) => Some(HybridPirMessage::Error {
+                code,
+                message: error.to_string(),
+            }),
+            (_, None) => None,
+        }
+    }
+
+    /**
+     * Wait for the client to start its next query cycle. Returns false if the
+     * client went away or idled out, or the server is shutting down, all of
+     * which just mean the connection should be closed.
+     *
+     * With TLS, this only looks at the encrypted stream. That's fine, since
+     * clients don't send anything before they got the previous response, so
+     * there is no decrypted data left over in between.
+     */
+    fn await_hello(stream: &TcpStream, stopping: &AtomicBool) -> Result<bool, HybridPirError> {
+        let deadline = Instant::now() + IDLE_TIMEOUT;
+
+        stream.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+
+        let result = loop {
+            if stopping.load(Ordering::SeqCst) {
+                break Ok(false);
+            }
+
+            match stream.peek(&mut [0]) {
+                Ok(0) => break Ok(false),
+                Ok(_) => break Ok(true),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    if Instant::now() >= deadline {
+                        break Ok(false);
+                    }
+                },
+                Err(e) => break Err(e.into()),
+            }
+        };
+
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+
+        result
+    }
+
+    /**
+     * Have the client present its token, if the server is set up to check
+     * them, and return the identity the authorizer associates it with.
+     */
+    fn authenticate(&self,
+        mut stream: &mut Stream,
+        peer: SocketAddr,
+        state: &ServerState
+    ) -> Result<Option<String>, HybridPirError> {
+        let authorizer = match state.config.authorizer.as_ref() {
+            Some(authorizer) => authorizer,
+            None => return Ok(None),
+        };
+
+        state.metrics.send(&mut stream, &HybridPirMessage::AuthRequired)?;
+
+        let token = match state.metrics.receive(&mut stream, &state.config.size_limits)? {
+            HybridPirMessage::Authenticate(token) => Ok(token),
+            m => Err(HybridPirError::unexpected(&m))
+        }?;
+
+        let client = state.authorize(authorizer, peer, &token)?;
+        debug!("[{:?}] Authenticated as {}.", peer, client);
+
+        Ok(Some(client))
     }
 
-    pub fn handle_connection(&self, mut stream: TcpStream) -> Result<(), Error> {
-        let t0 = Instant::now();
+    /**
+     * Look up the SealPIR key for the given session, having the client
+     * register it first if we don't know it yet.
+     */
+    fn session_key(&self,
+        mut stream: &mut Stream,
+        peer: SocketAddr,
+        session: u64,
+        state: &ServerState
+    ) -> Result<Arc<Vec<u8>>, HybridPirError> {
+        if let Some(key) = self.sealpir_key(session) {
+            return Ok(key);
+        }
+
+        let tk = Instant::now();
 
-        stream.set_read_timeout(Some(Duration::from_secs(60)))?;
-        stream.set_write_timeout(Some(Duration::from_secs(60)))?;
+        state.metrics.send(&mut stream, &HybridPirMessage::KeyRequired)?;
 
-        debug!("[{:?}] Accepting connection, waiting for hello...", stream.peer_addr().unwrap());
+        let key = match state.metrics.receive(&mut stream, &state.config.size_limits)? {
+            HybridPirMessage::RegisterKey(key) => Ok(key),
+            m => Err(HybridPirError::unexpected(&m))
+        }?;
+
+        let key = self.register_session_key(session, key)?;
+
+        state.metrics.record(Phase::KeyRegistration, tk.elapsed());
+
+        debug!("[{:?}] Registered key ({:.4}ms).",
+            peer,
+            tk.elapsed().as_secs_f64() * 1000.0);
+
+        Ok(key)
+    }
+
+    /**
+     * Take `count` seeds from the RaidPIR queue according to `policy`,
+     * returning None if they're refused because the queue ran dry.
+     */
+    pub(crate) fn queued_seeds(&self, count: usize, policy: EmptyQueue) -> Option<Vec<u128>> {
+        self.raidpir().take(count, policy)
+    }
 
-        // Receive init message.
-        match HybridPirMessage::read_from(&mut stream)? {
-            HybridPirMessage::Hello => {},
-            _ => {
-                return Err(Error::new(ErrorKind::Other, "Unexpected Response."));
+    /**
+     * Take seeds from the RaidPIR queue for a query cycle. If they're refused
+     * because the queue ran dry, the client is told the server is busy.
+     */
+    fn take_seeds(&self,
+        peer: SocketAddr,
+        count: usize,
+        state: &ServerState
+    ) -> Result<Vec<u128>, HybridPirError> {
+        match self.queued_seeds(count, state.config.when_queue_empty) {
+            Some(seeds) => Ok(seeds),
+            None => {
+                warn!("[{:?}] RaidPIR queue empty, rejecting query.", peer);
+                Err(HybridPirError::Overloaded { peer: None })
             }
         }
+    }
 
-        debug!("[{:?}] Received hello ({:.4}ms), sending seed...",
-            stream.peer_addr().unwrap(),
-            t0.elapsed().as_secs_f64() * 1000.0);
+    fn handle_query(&self,
+        mut stream: &mut Stream,
+        peer: SocketAddr,
+        session: u64,
+        sealpir_key: &Vec<u8>,
+        compression: Compression,
+        state: &ServerState
+    ) -> Result<(), HybridPirError> {
+        debug!("[{:?}] Sending seed...", peer);
 
         let t1 = Instant::now();
 
         // Send seeds
-        let seed = self.seed();
+        let seed = self.take_seeds(peer, 1, state)?[0];
         let msg = HybridPirMessage::Seed(seed);
-        msg.write_to(&mut stream)?;
+        state.metrics.send(&mut stream, &msg)?;
+
+        state.metrics.record(Phase::Seed, t1.elapsed());
 
         debug!("[{:?}] Seed sent ({:.4}ms), waiting for query...",
-            stream.peer_addr().unwrap(),
+            peer,
             t1.elapsed().as_secs_f64() * 1000.0);
 
         let t2 = Instant::now();
 
         // Receive query
-        let (raidpir_query, sealpir_key, sealpir_query) = match HybridPirMessage::read_from(&mut stream)? {
-            HybridPirMessage::Query(a,b,c) => Ok((a,b,c)),
-            _ => Err(Error::new(ErrorKind::Other, "Unexpected Response."))
+        let (raidpir_query, sealpir_query) = match state.metrics.receive(&mut stream, &state.config.size_limits)? {
+            HybridPirMessage::Query(a,b) => Ok((a,b)),
+            m => Err(HybridPirError::unexpected(&m))
         }?;
 
         // Convert raidpir_query to bitvec
-        assert!(std::mem::size_of::<&usize>() == std::mem::size_of::<&u64>());
         let raidpir_query: BitVec<Lsb0, u8> = BitVec::from_vec(raidpir_query);
+        self.check_query(&raidpir_query, std::slice::from_ref(&sealpir_query))?;
+
+        state.metrics.record(Phase::Query, t2.elapsed());
 
         debug!("[{:?}] Received query ({:.4}ms), calculating response...",
-            stream.peer_addr().unwrap(),
+            peer,
             t2.elapsed().as_secs_f64() * 1000.0);
 
         let t3 = Instant::now();
 
-        let response = self.response(seed, &raidpir_query, &sealpir_key, &sealpir_query);
+        let response = self.session_response(seed, &raidpir_query, session, sealpir_key, &sealpir_query);
+
+        state.metrics.record(Phase::Response, t3.elapsed());
 
         debug!("[{:?}] Calculated response ({:.4}ms), sending response...",
-            stream.peer_addr().unwrap(),
+            peer,
+            t3.elapsed().as_secs_f64() * 1000.0);
+
+        let t4 = Instant::now();
+
+        self.send_response(&mut stream, HybridPirMessage::Response(response), compression, state)?;
+
+        state.metrics.record(Phase::Send, t4.elapsed());
+
+        debug!("[{:?}] Sent response ({:.4}ms).",
+            peer,
+            t4.elapsed().as_secs_f64() * 1000.0);
+
+        Ok(())
+    }
+
+    fn handle_batch_query(&self,
+        mut stream: &mut Stream,
+        peer: SocketAddr,
+        session: u64,
+        sealpir_key: &Vec<u8>,
+        count: usize,
+        compression: Compression,
+        state: &ServerState
+    ) -> Result<(), HybridPirError> {
+        if count == 0 || count > MAX_BATCH_SIZE {
+            return Err(HybridPirError::protocol(format!("Invalid batch size {}.", count)));
+        }
+
+        let t1 = Instant::now();
+
+        // Send seeds, one per RaidPIR chunk queried
+        let seeds = self.take_seeds(peer, count, state)?;
+        let msg = HybridPirMessage::Seeds(seeds.clone());
+        state.metrics.send(&mut stream, &msg)?;
+
+        state.metrics.record(Phase::Seed, t1.elapsed());
+
+        debug!("[{:?}] {} seeds sent ({:.4}ms), waiting for query...",
+            peer,
+            count,
+            t1.elapsed().as_secs_f64() * 1000.0);
+
+        let t2 = Instant::now();
+
+        // Receive query
+        let chunk_queries = match state.metrics.receive(&mut stream, &state.config.size_limits)? {
+            HybridPirMessage::BatchQuery(q) if q.len() == count => Ok(q),
+            m => Err(HybridPirError::unexpected(&m))
+        }?;
+
+        let (raidpir_queries, sealpir_queries): (Vec<BitVec<Lsb0, u8>>, Vec<Vec<PirQuery>>) = chunk_queries
+            .into_iter()
+            .map(|q| (BitVec::from_vec(q.raidpir_query), q.sealpir_queries))
+            .unzip();
+
+        for (raidpir_query, sealpir_queries) in raidpir_queries.iter().zip(sealpir_queries.iter()) {
+            self.check_query(raidpir_query, sealpir_queries)?;
+        }
+
+        state.metrics.record(Phase::Query, t2.elapsed());
+
+        debug!("[{:?}] Received batch query ({:.4}ms), calculating response...",
+            peer,
+            t2.elapsed().as_secs_f64() * 1000.0);
+
+        let t3 = Instant::now();
+
+        let response = self.session_response_batch(&seeds, &raidpir_queries, session, sealpir_key, &sealpir_queries)?;
+
+        state.metrics.record(Phase::Response, t3.elapsed());
+
+        debug!("[{:?}] Calculated batch response ({:.4}ms), sending response...",
+            peer,
             t3.elapsed().as_secs_f64() * 1000.0);
 
         let t4 = Instant::now();
 
-        let msg = HybridPirMessage::Response(response);
-        msg.write_to(&mut stream)?;
+        self.send_response(&mut stream, HybridPirMessage::BatchResponse(response), compression, state)?;
+
+        state.metrics.record(Phase::Send, t4.elapsed());
 
-        stream.shutdown(std::net::Shutdown::Both)?;
+        debug!("[{:?}] Sent batch response ({:.4}ms).",
+            peer,
+            t4.elapsed().as_secs_f64() * 1000.0);
+
+        Ok(())
+    }
 
-        debug!("[{:?}] Sent response ({:.4}ms). Total elapsed: {:.4}ms",
-            stream.peer_addr().unwrap(),
-            t4.elapsed().as_secs_f64() * 1000.0,
-            t0.elapsed().as_secs_f64() * 1000.0);
+    /**
+     * Send a response in one piece, or streamed in parts if it's too large
+     * for that.
+     */
+    fn send_response(&self,
+        stream: &mut Stream,
+        response: HybridPirMessage,
+        compression: Compression,
+        state: &ServerState
+    ) -> Result<(), HybridPirError> {
+        let (first, parts) = ResponseParts::split(response, stream.encoding, state.config.response_part_size)?;
+        state.metrics.send_compressed(stream, &first, compression)?;
 
-        // Done, use this thread to rebuild RaidPir queue
-        self.preprocess();
+        for part in parts.into_iter().flatten() {
+            state.metrics.send_compressed(stream, &part?, compression)?;
+        }
 
         Ok(())
     }
 }
+
+/**
+ * A server running in the background, as started by
+ * `HybridPirServer::accept_connections`. Dropping the handle shuts the server
+ * down, like `shutdown`.
+ */
+pub struct ServerHandle {
+    server: HybridPirServer,
+    local_addr: SocketAddr,
+    metrics_addr: Option<SocketAddr>,
+    state: Arc<ServerState>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl ServerHandle {
+    /**
+     * Address the server is listening on.
+     */
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /**
+     * Address metrics are served on, if enabled in `ServerConfig`.
+     */
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /**
+     * Number of RaidPIR seeds currently precomputed, see `ServerConfig`.
+     */
+    pub fn queue_depth(&self) -> usize {
+        self.server.queue_depth()
+    }
+
+    /**
+     * Everything recorded since the server was started.
+     */
+    pub fn stats(&self) -> Stats {
+        self.state.metrics.stats(self.queue_depth())
+    }
+
+    /**
+     * Stop accepting connections, let queries already in progress finish and
+     * wait for the server to stop. Idle connections are closed right away.
+     */
+    pub fn shutdown(&self) {
+        if !self.state.stopping.swap(true, Ordering::SeqCst) {
+            debug!("Shutting down server on {:?}...", self.local_addr);
+
+            // Listeners only notice once they get another connection
+            Self::wake(self.local_addr);
+            if let Some(addr) = self.metrics_addr {
+                Self::wake(addr);
+            }
+        }
+
+        self.join();
+    }
+
+    pub(crate) fn wake(addr: SocketAddr) {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+
+        if let Err(e) = TcpStream::connect((ip, addr.port())) {
+            error!("Could not wake up listener on {:?}: {}", addr, e);
+        }
+    }
+
+    /**
+     * Wait for the server to stop, which only happens after `shutdown`.
+     */
+    pub fn join(&self) {
+        for thread in self.threads.lock().unwrap().drain(..) {
+            if thread.join().is_err() {
+                error!("Server thread panicked.");
+            }
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
@@ -0,0 +1,155 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+
+#[cfg(feature = "tls")]
+use rustls::{ClientConnection, ServerConnection, StreamOwned};
+
+use crate::error::HybridPirError;
+use crate::types::{Compression, Encoding, Frame, HybridPirMessage, SizeLimits};
+
+/// Plaintext buffered before it's encrypted and sent as a TLS record.
+#[cfg(feature = "tls")]
+const TLS_WRITE_BUFFER: usize = 16 * 1024;
+
+/**
+ * Connection between client and server, either plain TCP or TLS on top of it.
+ *
+ * Messages are serialized in many small writes, which would each end up in a
+ * TLS record of their own, so TLS writes are buffered until flushed.
+ */
+pub(crate) struct Stream {
+    transport: Transport,
+    /// Encoding messages are sent in. Updated with every message received,
+    /// so servers answer clients in the encoding they asked in.
+    pub(crate) encoding: Encoding,
+}
+
+enum Transport {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    TlsClient(Box<StreamOwned<ClientConnection, TcpStream>>, Vec<u8>),
+    #[cfg(feature = "tls")]
+    TlsServer(Box<StreamOwned<ServerConnection, TcpStream>>, Vec<u8>),
+}
+
+impl Stream {
+    fn new(transport: Transport) -> Self {
+        Self {
+            transport,
+            encoding: Encoding::Bincode,
+        }
+    }
+
+    pub(crate) fn plain(stream: TcpStream) -> Self {
+        Self::new(Transport::Plain(stream))
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls_client(connection: ClientConnection, stream: TcpStream) -> Self {
+        Self::new(Transport::TlsClient(Box::new(StreamOwned::new(connection, stream)), Vec::new()))
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls_server(connection: ServerConnection, stream: TcpStream) -> Self {
+        Self::new(Transport::TlsServer(Box::new(StreamOwned::new(connection, stream)), Vec::new()))
+    }
+
+    /**
+     * Send a message in this stream's encoding.
+     */
+    pub(crate) fn send<M: Frame>(&mut self, message: &M, compression: Compression) -> Result<(), HybridPirError> {
+        let encoding = self.encoding;
+        message.write_frame(self, encoding, compression)
+    }
+
+    /**
+     * Receive a message, answering in its encoding from now on.
+     */
+    pub(crate) fn receive(&mut self, limits: &SizeLimits) -> Result<HybridPirMessage, HybridPirError> {
+        let (message, encoding) = HybridPirMessage::read_frame(self, limits)?;
+        self.encoding = encoding;
+        Ok(message)
+    }
+
+    /**
+     * The underlying TCP connection, for timeouts and the like. Anything read
+     * from or written to it directly bypasses TLS.
+     */
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match &self.transport {
+            Transport::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Transport::TlsClient(stream, _) => stream.get_ref(),
+            #[cfg(feature = "tls")]
+            Transport::TlsServer(stream, _) => stream.get_ref(),
+        }
+    }
+
+    pub(crate) fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    /**
+     * Close the connection, telling the peer first if this is TLS.
+     */
+    pub(crate) fn shutdown(&mut self) {
+        match &mut self.transport {
+            Transport::Plain(_) => {},
+            #[cfg(feature = "tls")]
+            Transport::TlsClient(stream, _) => stream.conn.send_close_notify(),
+            #[cfg(feature = "tls")]
+            Transport::TlsServer(stream, _) => stream.conn.send_close_notify(),
+        }
+
+        self.flush().ok();
+        self.tcp().shutdown(Shutdown::Both).ok();
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.transport {
+            Transport::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Transport::TlsClient(stream, _) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Transport::TlsServer(stream, _) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.transport {
+            Transport::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Transport::TlsClient(_, buffer) | Transport::TlsServer(_, buffer) => {
+                buffer.extend_from_slice(buf);
+
+                if buffer.len() >= TLS_WRITE_BUFFER {
+                    self.flush()?;
+                }
+
+                Ok(buf.len())
+            },
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.transport {
+            Transport::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Transport::TlsClient(stream, buffer) => {
+                stream.write_all(buffer)?;
+                buffer.clear();
+                stream.flush()
+            },
+            #[cfg(feature = "tls")]
+            Transport::TlsServer(stream, buffer) => {
+                stream.write_all(buffer)?;
+                buffer.clear();
+                stream.flush()
+            },
+        }
+    }
+}
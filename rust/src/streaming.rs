@@ -0,0 +1,183 @@
+//! Responses too large for a single frame are streamed: `ResponseStart`
+//! announces the serialized size of every SealPIR reply, followed by
+//! `ResponsePart`s carrying the replies in order, each split into pieces of
+//! bounded size. Parts never span two replies, so clients can decode every
+//! reply as soon as its last part is in. SealPIR can only decode whole
+//! replies, so only batch responses get decoded early, a single reply waits
+//! until all of it is in.
+
+use std::convert::TryFrom;
+
+use sealpir::PirReply;
+
+use crate::error::HybridPirError;
+use crate::types::{self, Encoding, HybridPirMessage};
+
+/**
+ * The parts of a streamed response, serialized one reply at a time as they
+ * are sent.
+ */
+pub(crate) struct ResponseParts {
+    replies: std::vec::IntoIter<PirReply>,
+    encoding: Encoding,
+    part_size: usize,
+    current: Vec<u8>,
+    offset: usize,
+}
+
+impl ResponseParts {
+    /**
+     * Split a `Response` or `BatchResponse` larger than `part_size` bytes.
+     * Returns the message to send first and the parts to follow it, if any.
+     * Anything else, and responses small enough, are sent as they are.
+     */
+    pub(crate) fn split(
+        response: HybridPirMessage,
+        encoding: Encoding,
+        part_size: usize
+    ) -> Result<(HybridPirMessage, Option<Self>), HybridPirError> {
+        let sizes = match &response {
+            HybridPirMessage::Response(reply) => vec![types::serialized_size(reply, encoding)?],
+            HybridPirMessage::BatchResponse(replies) => replies
+                .iter()
+                .flatten()
+                .map(|reply| types::serialized_size(reply, encoding))
+                .collect::<Result<Vec<u64>, HybridPirError>>()?,
+            _ => return Ok((response, None)),
+        };
+
+        if sizes.iter().sum::<u64>() <= part_size as u64 {
+            return Ok((response, None));
+        }
+
+        let sizes = sizes
+            .into_iter()
+            .map(|size| u32::try_from(size)
+                .map_err(|_| HybridPirError::protocol(format!("SealPIR reply of {} bytes is too large to send.", size))))
+            .collect::<Result<Vec<u32>, HybridPirError>>()?;
+
+        let replies: Vec<PirReply> = match response {
+            HybridPirMessage::Response(reply) => vec![reply],
+            HybridPirMessage::BatchResponse(replies) => replies.into_iter().flatten().collect(),
+            _ => unreachable!(),
+        };
+
+        let parts = Self {
+            replies: replies.into_iter(),
+            encoding,
+            part_size: part_size.max(1),
+            current: Vec::new(),
+            offset: 0,
+        };
+
+        Ok((HybridPirMessage::ResponseStart(sizes), Some(parts)))
+    }
+}
+
+impl Iterator for ResponseParts {
+    type Item = Result<HybridPirMessage, HybridPirError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset == self.current.len() {
+            self.current = match types::serialize(&self.replies.next()?, self.encoding) {
+                Ok(serialized) => serialized,
+                Err(e) => return Some(Err(e)),
+            };
+            self.offset = 0;
+        }
+
+        let end = self.current.len().min(self.offset + self.part_size);
+        let part = self.current[self.offset..end].to_vec();
+        self.offset = end;
+
+        Some(Ok(HybridPirMessage::ResponsePart(part)))
+    }
+}
+
+/**
+ * Puts a streamed response back together on the client side, handing out
+ * every reply as soon as it's complete.
+ */
+pub(crate) struct ReplyAssembler {
+    sizes: Vec<u32>,
+    encoding: Encoding,
+    next: usize,
+    current: Vec<u8>,
+    received: u64,
+    total: u64,
+}
+
+impl ReplyAssembler {
+    /**
+     * Start on a response announced with the given reply sizes, which has to
+     * hold `count` replies of no more than `limit` bytes in total.
+     */
+    pub(crate) fn new(sizes: Vec<u32>, count: usize, limit: u32, encoding: Encoding) -> Result<Self, HybridPirError> {
+        if sizes.len() != count {
+            return Err(HybridPirError::protocol(format!("Expected {} replies, server announced {}.", count, sizes.len())));
+        }
+
+        if sizes.contains(&0) {
+            return Err(HybridPirError::protocol("Server announced an empty reply."));
+        }
+
+        let total = sizes.iter().map(|size| *size as u64).sum();
+        if total > limit as u64 {
+            return Err(HybridPirError::protocol(format!("Response of {} bytes exceeds limit of {}.", total, limit)));
+        }
+
+        Ok(Self {
+            sizes,
+            encoding,
+            next: 0,
+            current: Vec::new(),
+            received: 0,
+            total,
+        })
+    }
+
+    /**
+     * Add the next part, returning the reply it completes along with its
+     * position in the response, if any.
+     */
+    pub(crate) fn push(&mut self, part: Vec<u8>) -> Result<Option<(usize, PirReply)>, HybridPirError> {
+        let size = match self.sizes.get(self.next) {
+            Some(size) => *size as usize,
+            None => return Err(HybridPirError::protocol("More response parts than announced.")),
+        };
+
+        if part.is_empty() || self.current.len() + part.len() > size {
+            return Err(HybridPirError::protocol(format!("Part of {} bytes doesn't fit reply {} of {} bytes.",
+                part.len(), self.next, size)));
+        }
+
+        self.received += part.len() as u64;
+
+        if self.current.is_empty() {
+            self.current = part;
+        } else {
+            self.current.extend_from_slice(&part);
+        }
+
+        if self.current.len() < size {
+            return Ok(None);
+        }
+
+        let reply = types::deserialize(&std::mem::take(&mut self.current), self.encoding)?;
+        self.next += 1;
+
+        Ok(Some((self.next - 1, reply)))
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.next == self.sizes.len()
+    }
+
+    pub(crate) fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub(crate) fn total(&self) -> u64 {
+        self.total
+    }
+}
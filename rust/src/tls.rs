@@ -0,0 +1,87 @@
+//! TLS for connections between clients and servers.
+//!
+//! Every server only sees its own share of a query, but an eavesdropper on
+//! the paths to enough servers sees all of them and can put the query back
+//! together. Outside of a trusted network, connections should be encrypted.
+//!
+//! Servers are set up with `ServerConfig::tls`, clients with
+//! `HybridPirClient::set_tls` for every server. Only the blocking client and
+//! server support TLS for now, not the async ones.
+//!
+//! ```no_run
+//! use hybridpir::tls;
+//!
+//! let server = tls::server_config(
+//!     &std::fs::read("server.crt").unwrap(),
+//!     &std::fs::read("server.key").unwrap()
+//! ).unwrap();
+//!
+//! let client = tls::client_config(&std::fs::read("ca.crt").unwrap()).unwrap();
+//! ```
+
+use std::io::BufReader;
+use std::sync::Arc;
+
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+
+use crate::error::HybridPirError;
+
+/**
+ * Server side TLS setup from a PEM encoded certificate chain, leaf first, and
+ * its private key.
+ */
+pub fn server_config(cert_chain: &[u8], private_key: &[u8]) -> Result<Arc<ServerConfig>, HybridPirError> {
+    let certs = certificates(cert_chain)?;
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(private_key))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key) => Some(key),
+            rustls_pemfile::Item::RSAKey(key) => Some(key),
+            rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or(HybridPirError::InvalidParameters("No private key found.".into()))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, PrivateKey(key))
+        .map_err(|e| HybridPirError::InvalidParameters(format!("Invalid certificate: {}", e)))?;
+
+    Ok(Arc::new(config))
+}
+
+/**
+ * Client side TLS setup, trusting only the given PEM encoded root
+ * certificates. Servers operated by different parties will usually need
+ * different ones.
+ */
+pub fn client_config(roots: &[u8]) -> Result<Arc<ClientConfig>, HybridPirError> {
+    let mut store = RootCertStore::empty();
+
+    for cert in certificates(roots)? {
+        store.add(&cert)
+            .map_err(|e| HybridPirError::InvalidParameters(format!("Invalid root certificate: {}", e)))?;
+    }
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(store)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+fn certificates(pem: &[u8]) -> Result<Vec<Certificate>, HybridPirError> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(pem))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    if certs.is_empty() {
+        return Err(HybridPirError::InvalidParameters("No certificates found.".into()));
+    }
+
+    Ok(certs)
+}
@@ -1,45 +1,423 @@
+use std::convert::TryInto;
 use std::io::{Error, ErrorKind, Read, Write};
 
 use bitvec::prelude::*;
 use sealpir::{PirQuery, PirReply};
-use serde::{Serialize, Deserialize};
-use bincode;
+use serde::{Serialize, Serializer, Deserialize};
+use serde::ser::SerializeTupleVariant;
+use serde::de::DeserializeOwned;
+use bincode::{self, Options};
+use sha2::{Digest, Sha256};
+
+use crate::error::HybridPirError;
+use crate::params::{HybridPirParams, ParamsFingerprint};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum HybridPirMessage {
-    Hello,
-    Seed(u128),
-    Query(
+    Hello(u64, ParamsFingerprint, Vec<Compression>),
+    KeyRequired,
+    RegisterKey(
         #[serde(with = "serde_bytes")]
         Vec<u8>,
+    ),
+    Seed(u128),
+    Query(
         #[serde(with = "serde_bytes")]
         Vec<u8>,
         PirQuery
     ),
     Response(PirReply),
+    BatchHello(u64, ParamsFingerprint, u32, Vec<Compression>),
+    ParamsMismatch(ParamsFingerprint),
+    Seeds(Vec<u128>),
+    BatchQuery(Vec<ChunkQuery>),
+    BatchResponse(Vec<Vec<PirReply>>),
+    Close,
+    AuthRequired,
+    Authenticate(
+        #[serde(with = "serde_bytes")]
+        Vec<u8>,
+    ),
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    UseCompression(Compression),
+    ResponseStart(Vec<u32>),
+    ResponsePart(
+        #[serde(with = "serde_bytes")]
+        Vec<u8>,
+    ),
+}
+
+/**
+ * How a frame body is compressed. Clients list the methods they'd like in
+ * their hello, best first, and the server answers with the first one it
+ * supports as well. Both sides then compress large messages that way for the
+ * rest of the query cycle.
+ *
+ * Only `None` is supported without the `compression` feature.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+impl Compression {
+    /**
+     * Every method this build can compress and decompress, best first.
+     */
+    pub fn supported() -> Vec<Compression> {
+        let mut supported = Vec::new();
+
+        #[cfg(feature = "compression")]
+        supported.push(Compression::Zstd);
+
+        supported.push(Compression::None);
+        supported
+    }
+
+    pub fn is_supported(&self) -> bool {
+        Self::supported().contains(self)
+    }
+
+    /**
+     * Pick the first of the methods a client offered that we support and
+     * allow.
+     *
+     * ```
+     * use hybridpir::types::Compression;
+     *
+     * let choice = Compression::negotiate(&[Compression::Zstd], &[Compression::None]);
+     * assert!(choice == Compression::None);
+     * ```
+     */
+    pub fn negotiate(offered: &[Compression], allowed: &[Compression]) -> Compression {
+        offered
+            .iter()
+            .find(|c| allowed.contains(c) && c.is_supported())
+            .copied()
+            .unwrap_or(Compression::None)
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * How messages are serialized. Bincode is what this crate has always spoken
+ * and the most compact, CBOR follows the schema in PROTOCOL.md and is meant
+ * for clients written in other languages.
+ *
+ * Every frame header names the encoding of its body, and servers answer in
+ * whatever encoding the client last used. Only `Bincode` is supported
+ * without the `cbor` feature.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Bincode,
+    Cbor,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Bincode
+    }
+}
+
+impl Encoding {
+    /**
+     * Every encoding this build can read and write.
+     */
+    pub fn supported() -> Vec<Encoding> {
+        vec![
+            Encoding::Bincode,
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor,
+        ]
+    }
+
+    pub fn is_supported(&self) -> bool {
+        Self::supported().contains(self)
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Encoding::Bincode => 0,
+            Encoding::Cbor => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Encoding::Bincode),
+            1 => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Why the server gave up on a connection, sent along with a human readable
+ * message in `HybridPirMessage::Error`.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The client sent something that doesn't fit the protocol.
+    Protocol,
+    /// Too many connections, or no RaidPIR seeds to hand out.
+    Overloaded,
+    /// Missing or rejected credentials.
+    Unauthorized,
+    /// The client used up its query quota.
+    RateLimited,
+    /// Something went wrong on the server itself.
+    Internal,
+}
+
+/**
+ * Part of a batch query concerning a single RaidPIR chunk: one RaidPIR query,
+ * and a SealPIR query for every element requested from that chunk.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkQuery {
+    #[serde(with = "serde_bytes")]
+    pub raidpir_query: Vec<u8>,
+    pub sealpir_queries: Vec<PirQuery>,
+}
+
+/**
+ * Derive the session ID a SealPIR Galois key is cached under on the server:
+ * the first 8 bytes of its SHA-256 hash, as a little endian integer.
+ *
+ * Session IDs are sent in the clear, so this has to be a cryptographic hash.
+ * Otherwise, anyone could register a key of their own under another client's
+ * session, and that client would silently get garbage back.
+ *
+ * ```
+ * use hybridpir::types::session_id;
+ *
+ * assert!(session_id(b"key") == session_id(b"key"));
+ * assert!(session_id(b"key") != session_id(b"other key"));
+ * ```
+ */
+pub fn session_id(sealpir_key: &[u8]) -> u64 {
+    let hash = Sha256::digest(sealpir_key);
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+pub(crate) fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Start of every frame, so that anything not speaking the protocol is
+/// turned away before it's read any further.
+pub const MAGIC: [u8; 4] = *b"HPIR";
+
+/// Version of the wire format. Peers only talk to the exact same version.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Magic, version, message type, compression, encoding and body length.
+pub const HEADER_SIZE: usize = 12;
+
+/// Bodies smaller than this are never compressed, it wouldn't save much.
+pub const COMPRESSION_THRESHOLD: u64 = 1024;
+
+#[cfg(feature = "compression")]
+const ZSTD_LEVEL: i32 = 3;
+
+/**
+ * Types of `HybridPirMessage`, numbered as in the type field of the frame
+ * header. This is the order of the `HybridPirMessage` variants.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MessageType {
+    Hello,
+    KeyRequired,
+    RegisterKey,
+    Seed,
+    Query,
+    Response,
+    BatchHello,
+    ParamsMismatch,
+    Seeds,
+    BatchQuery,
+    BatchResponse,
+    Close,
+    AuthRequired,
+    Authenticate,
+    Error,
+    UseCompression,
+    ResponseStart,
+    ResponsePart,
+}
+
+impl MessageType {
+    pub const ALL: [MessageType; 18] = [
+        MessageType::Hello,
+        MessageType::KeyRequired,
+        MessageType::RegisterKey,
+        MessageType::Seed,
+        MessageType::Query,
+        MessageType::Response,
+        MessageType::BatchHello,
+        MessageType::ParamsMismatch,
+        MessageType::Seeds,
+        MessageType::BatchQuery,
+        MessageType::BatchResponse,
+        MessageType::Close,
+        MessageType::AuthRequired,
+        MessageType::Authenticate,
+        MessageType::Error,
+        MessageType::UseCompression,
+        MessageType::ResponseStart,
+        MessageType::ResponsePart,
+    ];
+
+    /**
+     * Name of the message type, for logging, error messages and metrics.
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageType::Hello => "Hello",
+            MessageType::KeyRequired => "KeyRequired",
+            MessageType::RegisterKey => "RegisterKey",
+            MessageType::Seed => "Seed",
+            MessageType::Query => "Query",
+            MessageType::Response => "Response",
+            MessageType::BatchHello => "BatchHello",
+            MessageType::ParamsMismatch => "ParamsMismatch",
+            MessageType::Seeds => "Seeds",
+            MessageType::BatchQuery => "BatchQuery",
+            MessageType::BatchResponse => "BatchResponse",
+            MessageType::Close => "Close",
+            MessageType::AuthRequired => "AuthRequired",
+            MessageType::Authenticate => "Authenticate",
+            MessageType::Error => "Error",
+            MessageType::UseCompression => "UseCompression",
+            MessageType::ResponseStart => "ResponseStart",
+            MessageType::ResponsePart => "ResponsePart",
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        *self as u8
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        Self::ALL.get(tag as usize).copied()
+    }
+}
+
+/**
+ * Largest message body accepted for each message type, in bytes. Frames
+ * announcing a larger body are refused before any of it is read.
+ *
+ * The defaults leave plenty of room for the SealPIR keys, queries and replies
+ * of common parameters, and keep everything else small.
+ *
+ * ```
+ * use hybridpir::types::{MessageType, SizeLimits};
+ *
+ * let limits = SizeLimits::default()
+ *     .limit(MessageType::RegisterKey, 1 << 20);
+ *
+ * assert!(limits.get(MessageType::RegisterKey) == 1 << 20);
+ * assert!(limits.get(MessageType::Hello) == SizeLimits::default().get(MessageType::Hello));
+ * ```
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimits {
+    limits: [u32; MessageType::ALL.len()],
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        let mut limits = Self {
+            limits: [64 << 10; MessageType::ALL.len()],
+        };
+
+        for (message_type, bytes) in [
+            (MessageType::RegisterKey, 64 << 20),
+            (MessageType::Query, 64 << 20),
+            (MessageType::Response, 64 << 20),
+            (MessageType::BatchQuery, 256 << 20),
+            (MessageType::BatchResponse, 256 << 20),
+            (MessageType::ResponsePart, 16 << 20),
+        ].iter() {
+            limits = limits.limit(*message_type, *bytes);
+        }
+
+        limits
+    }
+}
+
+impl SizeLimits {
+    /**
+     * Set the limit for one message type.
+     */
+    pub fn limit(mut self, message_type: MessageType, bytes: u32) -> Self {
+        self.limits[message_type as usize] = bytes;
+        self
+    }
+
+    pub fn get(&self, message_type: MessageType) -> u32 {
+        self.limits[message_type as usize]
+    }
 }
 
 impl HybridPirMessage {
     /**
-     * Write message to target, serializing it to bincode.
+     * Write message to target as a single, uncompressed frame: header, then
+     * the message serialized to bincode.
      *
      * ```
-     * use hybridpir::types::HybridPirMessage;
+     * use hybridpir::types::{HybridPirMessage, HEADER_SIZE};
      *
      * let mut buffer: Vec<u8> = Vec::new();
      * let mut cursor = std::io::Cursor::new(buffer);
      *
      * let message = HybridPirMessage::Seed(1234);
      * message.write_to(&mut cursor).unwrap();
+     *
+     * assert!(&cursor.get_ref()[..4] == b"HPIR");
+     * assert!(cursor.get_ref().len() == HEADER_SIZE + 4 + 16);
      * ```
      */
-    pub fn write_to<W: Write>(&self, mut stream: &mut W) -> Result<(), std::io::Error> {
-        bincode::serialize_into(&mut stream, self)
-            .map_err(|e| Error::new(ErrorKind::Other, format!("{}", e)))
+    pub fn write_to<W: Write>(&self, stream: &mut W) -> Result<(), HybridPirError> {
+        self.write_encoded(stream, Encoding::Bincode, Compression::None)
     }
 
     /**
-     * Read message from stream and deserialize.
+     * Write message to target as a single frame in the given encoding,
+     * compressing the body if it's large enough to be worth it.
+     */
+    pub fn write_encoded<W: Write>(&self,
+        stream: &mut W,
+        encoding: Encoding,
+        compression: Compression
+    ) -> Result<(), HybridPirError> {
+        self.write_frame(stream, encoding, compression)
+    }
+
+    /**
+     * Read message from stream and deserialize, with the default size
+     * limits.
      *
      * ```
      * use std::io::{Seek, SeekFrom};
@@ -57,12 +435,361 @@ impl HybridPirMessage {
      * assert!(deserialized == HybridPirMessage::Seed(1234));
      * ```
      */
-    pub fn read_from<R: Read>(mut stream: &mut R) -> Result<Self, std::io::Error> {
-        bincode::deserialize_from(&mut stream)
-            .map_err(|e| Error::new(ErrorKind::Other, format!("{}", e)))
+    pub fn read_from<R: Read>(stream: &mut R) -> Result<Self, HybridPirError> {
+        Self::read_limited(stream, &SizeLimits::default())
+    }
+
+    /**
+     * Read message from stream and deserialize, refusing it if the header
+     * announces more than `limits` allows for its type.
+     *
+     * ```
+     * use std::io::{Seek, SeekFrom};
+     * use hybridpir::types::{HybridPirMessage, MessageType, SizeLimits};
+     *
+     * let mut buffer: Vec<u8> = Vec::new();
+     * let mut cursor = std::io::Cursor::new(buffer);
+     *
+     * let message = HybridPirMessage::RegisterKey(vec![0; 1024]);
+     * message.write_to(&mut cursor).unwrap();
+     *
+     * cursor.seek(SeekFrom::Start(0)).unwrap();
+     *
+     * let limits = SizeLimits::default().limit(MessageType::RegisterKey, 512);
+     * assert!(HybridPirMessage::read_limited(&mut cursor, &limits).is_err());
+     * ```
+     */
+    pub fn read_limited<R: Read>(stream: &mut R, limits: &SizeLimits) -> Result<Self, HybridPirError> {
+        Ok(Self::read_frame(stream, limits)?.0)
+    }
+
+    /**
+     * Like `read_limited`, but also return the encoding the message came in.
+     */
+    pub(crate) fn read_frame<R: Read>(stream: &mut R, limits: &SizeLimits) -> Result<(Self, Encoding), HybridPirError> {
+        let mut header = [0; HEADER_SIZE];
+        stream.read_exact(&mut header)?;
+
+        let length = Self::frame_length(&header, limits)?;
+
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body)?;
+
+        Self::decode(&header, &body, limits)
+    }
+
+    /**
+     * Check a frame header against the protocol and `limits`, returning the
+     * length of the body that follows.
+     */
+    pub(crate) fn frame_length(header: &[u8], limits: &SizeLimits) -> Result<usize, HybridPirError> {
+        if header[..4] != MAGIC {
+            return Err(HybridPirError::protocol("Not a HybridPIR message."));
+        }
+
+        if header[4] != PROTOCOL_VERSION {
+            return Err(HybridPirError::protocol(format!("Unsupported protocol version {}, expected {}.",
+                header[4], PROTOCOL_VERSION)));
+        }
+
+        let message_type = MessageType::from_tag(header[5])
+            .ok_or_else(|| HybridPirError::protocol(format!("Unknown message type {}.", header[5])))?;
+
+        match Compression::from_id(header[6]) {
+            Some(compression) if compression.is_supported() => {},
+            _ => return Err(HybridPirError::protocol(format!("Unsupported compression {}.", header[6]))),
+        }
+
+        match Encoding::from_id(header[7]) {
+            Some(encoding) if encoding.is_supported() => {},
+            _ => return Err(HybridPirError::protocol(format!("Unsupported encoding {}.", header[7]))),
+        }
+
+        let mut length = [0; 4];
+        length.copy_from_slice(&header[8..HEADER_SIZE]);
+        let length = u32::from_le_bytes(length);
+
+        if length > limits.get(message_type) {
+            return Err(HybridPirError::protocol(format!("{} of {} bytes exceeds limit of {}.",
+                message_type.name(), length, limits.get(message_type))));
+        }
+
+        Ok(length as usize)
+    }
+
+    /**
+     * Deserialize the body of a frame, which must hold exactly one message of
+     * the type given in the header. Compressed bodies may not grow beyond the
+     * size limit either.
+     *
+     * Returns the message along with the encoding it came in.
+     */
+    pub(crate) fn decode(header: &[u8], body: &[u8], limits: &SizeLimits) -> Result<(Self, Encoding), HybridPirError> {
+        let message_type = MessageType::from_tag(header[5])
+            .ok_or_else(|| HybridPirError::protocol(format!("Unknown message type {}.", header[5])))?;
+
+        let decompressed;
+
+        let body = match Compression::from_id(header[6]) {
+            Some(Compression::Zstd) => {
+                decompressed = zstd_decompress(body, limits.get(message_type))?;
+                &decompressed[..]
+            },
+            _ => body,
+        };
+
+        let encoding = Encoding::from_id(header[7])
+            .ok_or_else(|| HybridPirError::protocol(format!("Unsupported encoding {}.", header[7])))?;
+
+        let message: Self = deserialize(body, encoding)?;
+
+        if message.message_type() != message_type {
+            return Err(HybridPirError::protocol(format!("Frame announced {}, but contained {}.",
+                message_type.name(), message.name())));
+        }
+
+        Ok((message, encoding))
+    }
+
+    /**
+     * Turn an error reply into the error it reports, passing anything else
+     * through.
+     */
+    pub(crate) fn into_result(self) -> Result<Self, HybridPirError> {
+        match self {
+            HybridPirMessage::Error { code, message } => Err(HybridPirError::reply(code, message)),
+            message => Ok(message),
+        }
+    }
+
+    /**
+     * Name of the message type, for logging and error messages.
+     */
+    pub fn name(&self) -> &'static str {
+        self.message_type().name()
+    }
+}
+
+/**
+ * Anything sent as a single frame: messages, and borrowed stand-ins for them
+ * that serialize exactly the same way.
+ */
+pub(crate) trait Frame: Serialize {
+    /**
+     * Type of the message, given in the frame header.
+     */
+    fn message_type(&self) -> MessageType;
+
+    /**
+     * Write a single frame in the given encoding, compressing the body if
+     * it's large enough to be worth it.
+     */
+    fn write_frame<W: Write>(&self,
+        mut stream: &mut W,
+        encoding: Encoding,
+        compression: Compression
+    ) -> Result<(), HybridPirError> {
+        let compress = |length: u64| {
+            compression != Compression::None && length >= COMPRESSION_THRESHOLD && compression.is_supported()
+        };
+
+        let body = match encoding {
+            Encoding::Bincode => {
+                let length = bincode::serialized_size(self)?;
+
+                // No need to buffer the body if it's sent as is
+                if !compress(length) {
+                    write_header(stream, self.message_type(), encoding, Compression::None, length)?;
+                    bincode::serialize_into(&mut stream, self)?;
+                    return Ok(stream.flush()?);
+                }
+
+                bincode::serialize(self)?
+            },
+            Encoding::Cbor => serialize(self, encoding)?,
+        };
+
+        if !compress(body.len() as u64) {
+            write_header(stream, self.message_type(), encoding, Compression::None, body.len() as u64)?;
+            stream.write_all(&body)?;
+            return Ok(stream.flush()?);
+        }
+
+        let body = zstd_compress(&body)?;
+
+        write_header(stream, self.message_type(), encoding, compression, body.len() as u64)?;
+        stream.write_all(&body)?;
+        Ok(stream.flush()?)
+    }
+}
+
+impl Frame for HybridPirMessage {
+    fn message_type(&self) -> MessageType {
+        match self {
+            HybridPirMessage::Hello(_, _, _) => MessageType::Hello,
+            HybridPirMessage::KeyRequired => MessageType::KeyRequired,
+            HybridPirMessage::RegisterKey(_) => MessageType::RegisterKey,
+            HybridPirMessage::Seed(_) => MessageType::Seed,
+            HybridPirMessage::Query(_, _) => MessageType::Query,
+            HybridPirMessage::Response(_) => MessageType::Response,
+            HybridPirMessage::BatchHello(_, _, _, _) => MessageType::BatchHello,
+            HybridPirMessage::ParamsMismatch(_) => MessageType::ParamsMismatch,
+            HybridPirMessage::Seeds(_) => MessageType::Seeds,
+            HybridPirMessage::BatchQuery(_) => MessageType::BatchQuery,
+            HybridPirMessage::BatchResponse(_) => MessageType::BatchResponse,
+            HybridPirMessage::Close => MessageType::Close,
+            HybridPirMessage::AuthRequired => MessageType::AuthRequired,
+            HybridPirMessage::Authenticate(_) => MessageType::Authenticate,
+            HybridPirMessage::Error { .. } => MessageType::Error,
+            HybridPirMessage::UseCompression(_) => MessageType::UseCompression,
+            HybridPirMessage::ResponseStart(_) => MessageType::ResponseStart,
+            HybridPirMessage::ResponsePart(_) => MessageType::ResponsePart,
+        }
+    }
+}
+
+fn write_header<W: Write>(stream: &mut W,
+    message_type: MessageType,
+    encoding: Encoding,
+    compression: Compression,
+    length: u64
+) -> Result<(), HybridPirError> {
+    if length > u32::MAX as u64 {
+        return Err(HybridPirError::protocol(format!("{} of {} bytes is too large to send.",
+            message_type.name(), length)));
+    }
+
+    let mut header = [0; HEADER_SIZE];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4] = PROTOCOL_VERSION;
+    header[5] = message_type.tag();
+    header[6] = compression.id();
+    header[7] = encoding.id();
+    header[8..].copy_from_slice(&(length as u32).to_le_bytes());
+
+    Ok(stream.write_all(&header)?)
+}
+
+/**
+ * `HybridPirMessage::Query`, borrowing its contents. Clients send the same
+ * SealPIR query to every server, this saves copying it for each of them.
+ */
+pub(crate) struct QueryRef<'a> {
+    pub(crate) raidpir_query: &'a [u8],
+    pub(crate) sealpir_query: &'a PirQuery,
+}
+
+impl Serialize for QueryRef<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut variant = serializer.serialize_tuple_variant("HybridPirMessage", 4, "Query", 2)?;
+        variant.serialize_field(serde_bytes::Bytes::new(self.raidpir_query))?;
+        variant.serialize_field(self.sealpir_query)?;
+        variant.end()
+    }
+}
+
+impl Frame for QueryRef<'_> {
+    fn message_type(&self) -> MessageType {
+        MessageType::Query
+    }
+}
+
+#[cfg(feature = "compression")]
+fn zstd_compress(body: &[u8]) -> Result<Vec<u8>, HybridPirError> {
+    Ok(zstd::bulk::compress(body, ZSTD_LEVEL)?)
+}
+
+/**
+ * Decompress a frame body, giving up as soon as it grows beyond `limit`.
+ */
+#[cfg(feature = "compression")]
+fn zstd_decompress(body: &[u8], limit: u32) -> Result<Vec<u8>, HybridPirError> {
+    let mut decompressed = Vec::new();
+
+    zstd::stream::read::Decoder::new(body)?
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+
+    if decompressed.len() > limit as usize {
+        return Err(HybridPirError::protocol(format!("Decompressed message exceeds limit of {} bytes.", limit)));
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(not(feature = "compression"))]
+fn zstd_compress(_body: &[u8]) -> Result<Vec<u8>, HybridPirError> {
+    Err(HybridPirError::protocol("Compression not supported."))
+}
+
+#[cfg(not(feature = "compression"))]
+fn zstd_decompress(_body: &[u8], _limit: u32) -> Result<Vec<u8>, HybridPirError> {
+    Err(HybridPirError::protocol("Compression not supported."))
+}
+
+/**
+ * Serialize a value the same way message bodies are. Streamed responses are
+ * made up of SealPIR replies serialized like this.
+ */
+pub(crate) fn serialize<T: Serialize + ?Sized>(value: &T, encoding: Encoding) -> Result<Vec<u8>, HybridPirError> {
+    match encoding {
+        Encoding::Bincode => Ok(bincode::serialize(value)?),
+        Encoding::Cbor => cbor_serialize(value),
+    }
+}
+
+pub(crate) fn serialized_size<T: Serialize>(value: &T, encoding: Encoding) -> Result<u64, HybridPirError> {
+    match encoding {
+        Encoding::Bincode => Ok(bincode::serialized_size(value)?),
+        // No way to tell short of serializing it
+        Encoding::Cbor => Ok(cbor_serialize(value)?.len() as u64),
+    }
+}
+
+/**
+ * Deserialize a value that has to take up all of `body`.
+ */
+pub(crate) fn deserialize<T: DeserializeOwned>(body: &[u8], encoding: Encoding) -> Result<T, HybridPirError> {
+    match encoding {
+        Encoding::Bincode => Ok(bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(body.len() as u64)
+            .deserialize(body)?),
+        Encoding::Cbor => cbor_deserialize(body),
     }
 }
 
+#[cfg(feature = "cbor")]
+fn cbor_serialize<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, HybridPirError> {
+    let mut body = Vec::new();
+    ciborium::ser::into_writer(value, &mut body)?;
+    Ok(body)
+}
+
+#[cfg(feature = "cbor")]
+fn cbor_deserialize<T: DeserializeOwned>(mut body: &[u8]) -> Result<T, HybridPirError> {
+    let value = ciborium::de::from_reader(&mut body)?;
+
+    if !body.is_empty() {
+        return Err(HybridPirError::Deserialization {
+            peer: None,
+            reason: format!("{} bytes left over after message.", body.len()),
+        });
+    }
+
+    Ok(value)
+}
+
+#[cfg(not(feature = "cbor"))]
+fn cbor_serialize<T: Serialize + ?Sized>(_value: &T) -> Result<Vec<u8>, HybridPirError> {
+    Err(HybridPirError::protocol("CBOR not supported."))
+}
+
+#[cfg(not(feature = "cbor"))]
+fn cbor_deserialize<T: DeserializeOwned>(_body: &[u8]) -> Result<T, HybridPirError> {
+    Err(HybridPirError::protocol("CBOR not supported."))
+}
+
 // Everything below this point is just for the purposes of benchmarks
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -102,17 +829,7 @@ pub enum BenchmarkParams {
         redundancy: usize,
         russians: bool
     },
-    HybridPir{
-        db_size: usize,
-        element_size: usize,
-        raidpir_servers: usize,
-        raidpir_redundancy: usize,
-        raidpir_size: usize,
-        raidpir_russians: bool,
-        sealpir_poly_degree: u32,
-        sealpir_log: u32,
-        sealpir_d: u32
-    }
+    HybridPir(HybridPirParams),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -1,9 +1,20 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
 use rand::rngs::StdRng;
 use rand::{RngCore, SeedableRng};
 use sealpir::PirReply;
 
+use hybridpir::auth::{Authorizer, RateLimit};
 use hybridpir::client::HybridPirClient;
-use hybridpir::server::HybridPirServer;
+use hybridpir::error::HybridPirError;
+use hybridpir::keyword::KeywordLayout;
+use hybridpir::params::HybridPirParams;
+use hybridpir::planner::{candidates, plan, CostModel};
+use hybridpir::server::{EmptyQueue, HybridPirServer, ServerConfig, ServerHandle};
+use hybridpir::types::{ErrorCode, HybridPirMessage, MessageType, SizeLimits, HEADER_SIZE, MAGIC, PROTOCOL_VERSION};
 
 #[test]
 fn test_pir() {
@@ -11,10 +22,16 @@ fn test_pir() {
 
     let size = 1 << 20;
     let raidpir_servers = 2;
-    let raidpir_redundancy = 2;
-    let raidpir_size = 1 << 10;
     let index = size >> 1;
 
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_servers(raidpir_servers)
+        .raidpir_redundancy(2)
+        .raidpir_size(1 << 10)
+        .sealpir(2048, 12, 2)
+        .build()
+        .unwrap();
+
     let mut db: Vec<Vec<u8>> = Vec::with_capacity(size);
     for _i in 0..size {
         let mut buffer = vec![0; 8];
@@ -24,19 +41,13 @@ fn test_pir() {
     db[index] = b"deadbeef".to_vec();
 
     let mut servers: Vec<HybridPirServer> = (0..raidpir_servers)
-        .map(|i| HybridPirServer::new(
-            &db,
-            i, raidpir_servers, raidpir_redundancy, raidpir_size, false,
-            2048, 12, 2
-        )).collect();
+        .map(|i| HybridPirServer::new(&db, i, &params).unwrap()).collect();
 
-    let client = HybridPirClient::new(db.len(), 8,
-        raidpir_servers, raidpir_redundancy, raidpir_size,
-        2048, 12, 2);
+    let client = HybridPirClient::new(&params).unwrap();
 
     let seeds = servers.iter_mut().map(|s| s.seed()).collect();
 
-    let (raidpir_queries, sealpir_query) = client.query(index, &seeds);
+    let (raidpir_queries, sealpir_query) = client.query(index, &seeds).unwrap();
 
     let sealpir_key = client.sealpir_key();
 
@@ -46,20 +57,104 @@ fn test_pir() {
         .map(|(server, (seed, raidpir_query))| server.response(*seed, raidpir_query, sealpir_key, &sealpir_query))
         .collect();
 
-    let response = client.combine(index, responses);
+    let response = client.combine(index, responses).unwrap();
     assert!(response == b"deadbeef");
 }
 
+#[test]
+fn test_sealpir_contexts() {
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+    let other_params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 7)
+        .sealpir(2048, 12, 2)
+        .build()
+        .unwrap();
+
+    let servers: Vec<HybridPirServer> = (0..2)
+        .map(|i| HybridPirServer::new(&db, i, &params).unwrap())
+        .collect();
+    let other_servers: Vec<HybridPirServer> = (0..2)
+        .map(|i| HybridPirServer::new(&db, i, &other_params).unwrap())
+        .collect();
+
+    // Every query takes a SealPIR server from the cache and puts it back
+    let query = |servers: &[HybridPirServer], client: &HybridPirClient, index: usize| {
+        let seeds = servers.iter().map(|s| s.seed()).collect();
+        let (raidpir_queries, sealpir_query) = client.query(index, &seeds).unwrap();
+
+        let responses = servers
+            .iter()
+            .zip(seeds.iter().zip(raidpir_queries.iter()))
+            .map(|(server, (seed, raidpir_query))| {
+                server.response(*seed, raidpir_query, client.sealpir_key(), &sealpir_query)
+            })
+            .collect();
+
+        client.combine(index, responses).unwrap()
+    };
+
+    // Same key, different chunks every time
+    let client = HybridPirClient::new(&params).unwrap();
+    let other_client = HybridPirClient::new(&other_params).unwrap();
+    for index in [0, 17, 1000, 2049, size - 1].iter() {
+        assert!(query(&servers, &client, *index) == db[*index]);
+        assert!(query(&other_servers, &other_client, *index) == db[*index]);
+    }
+
+    // More clients than servers are kept, pushing out the first ones
+    let clients: Vec<HybridPirClient> = (0..20)
+        .map(|_| HybridPirClient::new(&params).unwrap())
+        .collect();
+    for (i, client) in clients.iter().enumerate() {
+        assert!(query(&servers, client, i * 100) == db[i * 100]);
+    }
+
+    for (i, client) in clients.iter().enumerate().rev() {
+        assert!(query(&servers, client, i * 100 + 1) == db[i * 100 + 1]);
+    }
+    assert!(query(&servers, &client, 42) == db[42]);
+
+    // Clones share the cache, queries running at the same time get a server each
+    let threads: Vec<std::thread::JoinHandle<()>> = (0..4)
+        .map(|t| {
+            let (servers, params, db) = (servers.clone(), params.clone(), db.clone());
+
+            std::thread::spawn(move || {
+                let client = HybridPirClient::new(&params).unwrap();
+                for index in (t..size).step_by(1000) {
+                    assert!(query(&servers, &client, index) == db[index]);
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
 #[test]
 fn test_tcp() {
     let mut prng = StdRng::from_entropy();
 
     let size = 1 << 20;
     let raidpir_servers = 2;
-    let raidpir_redundancy = 2;
-    let raidpir_size = 1 << 8;
     let index = size >> 1;
 
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_servers(raidpir_servers)
+        .raidpir_redundancy(2)
+        .raidpir_size(1 << 8)
+        .sealpir(2048, 12, 2)
+        .build()
+        .unwrap();
+
     let mut db: Vec<Vec<u8>> = Vec::with_capacity(size);
     for _i in 0..size {
         let mut buffer = vec![0; 8];
@@ -68,23 +163,1607 @@ fn test_tcp() {
     }
     db[index] = b"deadbeef".to_vec();
 
+    let mut handles = Vec::new();
     for i in 0..raidpir_servers {
-        let server = HybridPirServer::new(&db,
-            i, raidpir_servers, raidpir_redundancy, raidpir_size, false,
-            2048, 12, 2);
-
-        std::thread::spawn(move || {
-            server.accept_connections(("localhost", (7000 + i) as u16)).unwrap();
-        });
+        let server = HybridPirServer::new(&db, i, &params).unwrap();
+        handles.push(server.accept_connections(("localhost", 0)).unwrap());
     }
 
-    let client = HybridPirClient::new(db.len(), 8,
-        raidpir_servers, raidpir_redundancy, raidpir_size,
-        2048, 12, 2);
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let client = HybridPirClient::new(&params).unwrap();
 
     let response = client
-        .send_query(&[("localhost", 7000), ("localhost", 7001)], index)
+        .send_query(&targets, index)
         .unwrap();
 
     assert!(response == b"deadbeef");
 }
+
+#[test]
+fn test_key_registration() {
+    let mut prng = StdRng::from_entropy();
+
+    let size = 1 << 16;
+    let raidpir_servers = 2;
+    let index = size >> 1;
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_servers(raidpir_servers)
+        .raidpir_redundancy(2)
+        .raidpir_size(1 << 8)
+        .sealpir(2048, 12, 2)
+        .build()
+        .unwrap();
+
+    let mut db: Vec<Vec<u8>> = Vec::with_capacity(size);
+    for _i in 0..size {
+        let mut buffer = vec![0; 8];
+        prng.fill_bytes(&mut buffer);
+        db.push(buffer);
+    }
+    db[index] = b"deadbeef".to_vec();
+
+    let servers: Vec<HybridPirServer> = (0..raidpir_servers)
+        .map(|i| HybridPirServer::new(&db, i, &params).unwrap())
+        .collect();
+
+    let mut handles = Vec::new();
+    for server in servers.iter() {
+        let server = server.clone();
+        handles.push(server.accept_connections(("localhost", 0)).unwrap());
+    }
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let client = HybridPirClient::new(&params).unwrap();
+
+    assert!(servers.iter().all(|s| s.sealpir_key(client.session_id()).is_none()));
+
+    // First query registers the key, second one reuses it
+    for _i in 0..2 {
+        let response = client
+            .send_query(&targets, index)
+            .unwrap();
+
+        assert!(response == b"deadbeef");
+        assert!(servers.iter().all(|s| s.sealpir_key(client.session_id()).is_some()));
+    }
+
+    // Nobody can register another key under the client's session
+    let session = client.session_id();
+    let result = servers[0].register_session_key(session, b"not the key".to_vec());
+    assert!(matches!(result, Err(HybridPirError::Protocol { .. })));
+    assert!(*servers[0].sealpir_key(session).unwrap() == *client.sealpir_key());
+    assert!(servers[0].register_key(client.sealpir_key().clone()).unwrap() == session);
+}
+
+#[test]
+fn test_persistent_connections() {
+    let mut prng = StdRng::from_entropy();
+
+    let size = 1 << 16;
+    let raidpir_servers = 2;
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_servers(raidpir_servers)
+        .raidpir_redundancy(2)
+        .raidpir_size(1 << 8)
+        .sealpir(2048, 12, 2)
+        .build()
+        .unwrap();
+
+    let mut db: Vec<Vec<u8>> = Vec::with_capacity(size);
+    for _i in 0..size {
+        let mut buffer = vec![0; 8];
+        prng.fill_bytes(&mut buffer);
+        db.push(buffer);
+    }
+
+    let mut handles = Vec::new();
+    for i in 0..raidpir_servers {
+        let server = HybridPirServer::new(&db, i, &params).unwrap();
+        handles.push(server.accept_connections(("localhost", 0)).unwrap());
+    }
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let client = HybridPirClient::new(&params).unwrap();
+
+    for index in [1, size >> 1, size - 1].iter() {
+        let response = client.send_query(&targets, *index).unwrap();
+        assert!(response == db[*index]);
+    }
+
+    // Closing just means the next query opens new connections
+    client.close();
+
+    let response = client.send_query(&targets, 0).unwrap();
+    assert!(response == db[0]);
+}
+
+#[test]
+fn test_batch() {
+    let mut prng = StdRng::from_entropy();
+
+    let size = 1 << 16;
+    let raidpir_servers = 2;
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_servers(raidpir_servers)
+        .raidpir_redundancy(2)
+        .raidpir_size(1 << 8)
+        .sealpir(2048, 12, 2)
+        .build()
+        .unwrap();
+
+    let mut db: Vec<Vec<u8>> = Vec::with_capacity(size);
+    for _i in 0..size {
+        let mut buffer = vec![0; 8];
+        prng.fill_bytes(&mut buffer);
+        db.push(buffer);
+    }
+
+    let mut handles = Vec::new();
+    for i in 0..raidpir_servers {
+        let server = HybridPirServer::new(&db, i, &params).unwrap();
+        handles.push(server.accept_connections(("localhost", 0)).unwrap());
+    }
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let client = HybridPirClient::new(&params).unwrap();
+
+    // 0, 1 and 2 share a chunk, 1 is requested twice
+    let indices = [0, 1, 2, size >> 1, 1, size - 1];
+    assert!(client.batch_seeds(&indices) == 3);
+
+    let responses = client
+        .send_query_batch(&targets, &indices)
+        .unwrap();
+
+    assert!(responses.len() == indices.len());
+    for (index, response) in indices.iter().zip(responses.iter()) {
+        assert!(*response == db[*index]);
+    }
+}
+
+#[test]
+fn test_batch_padding() {
+    use hybridpir::client::BatchPadding;
+    use hybridpir::types::{ChunkQuery, Compression, Encoding};
+
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let mut client = HybridPirClient::new(&params).unwrap();
+    assert!(client.set_batch_padding(Some(BatchPadding { chunks: 0, queries_per_chunk: 1 })).is_err());
+    client.set_batch_padding(Some(BatchPadding { chunks: 4, queries_per_chunk: 3 })).unwrap();
+
+    // All in one chunk, and all in different ones
+    let grouped = [0, 1, 2];
+    let spread = [0, size >> 2, size >> 1, size - 1];
+
+    let frame = |indices: &[usize]| {
+        let seeds = vec![vec![1234; client.batch_seeds(indices)]; client.servers()];
+        let (raidpir_queries, sealpir_queries) = client.query_batch(indices, &seeds).unwrap();
+
+        let message = HybridPirMessage::BatchQuery(raidpir_queries[0]
+            .iter()
+            .zip(sealpir_queries.into_iter())
+            .map(|(raidpir_query, sealpir_queries)| ChunkQuery {
+                raidpir_query: raidpir_query.clone().into_vec(),
+                sealpir_queries,
+            })
+            .collect());
+
+        let mut frame = Vec::new();
+        message.write_encoded(&mut frame, Encoding::Bincode, Compression::None).unwrap();
+        (message, frame)
+    };
+
+    let (grouped_message, grouped_frame) = frame(&grouped);
+    let (spread_message, spread_frame) = frame(&spread);
+
+    let shape = |message: &HybridPirMessage| match message {
+        HybridPirMessage::BatchQuery(q) => q.iter().map(|q| q.sealpir_queries.len()).collect::<Vec<usize>>(),
+        _ => unreachable!(),
+    };
+
+    assert!(shape(&grouped_message) == vec![3; 4]);
+    assert!(shape(&spread_message) == vec![3; 4]);
+    assert!(grouped_frame.len() == spread_frame.len());
+    assert!(grouped_frame[..HEADER_SIZE] == spread_frame[..HEADER_SIZE]);
+
+    // Too many in one chunk
+    let result = client.query_batch(&[0, 1, 2, 3], &vec![vec![1234; 4]; client.servers()]);
+    assert!(matches!(result, Err(HybridPirError::InvalidParameters(_))));
+
+    let handles: Vec<ServerHandle> = (0..2)
+        .map(|i| {
+            HybridPirServer::new(&db, i, &params)
+                .unwrap()
+                .accept_connections(("localhost", 0))
+                .unwrap()
+        })
+        .collect();
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    for indices in [&grouped[..], &spread[..]].iter() {
+        let expected: Vec<Vec<u8>> = indices.iter().map(|i| db[*i].clone()).collect();
+        assert!(client.send_query_batch(&targets, indices).unwrap() == expected);
+    }
+
+    let result = client.send_query_batch(&targets, &[0, 1, 2, 3]);
+    assert!(matches!(result, Err(HybridPirError::InvalidParameters(_))));
+
+    client.close();
+
+    for handle in handles {
+        handle.shutdown();
+    }
+}
+
+#[test]
+fn test_errors() {
+    let db: Vec<Vec<u8>> = vec![vec![0; 8]; 1 << 12];
+
+    // RaidPIR size not a multiple of servers * 8
+    let params = HybridPirParams::builder(db.len(), 8).raidpir_size(100).build();
+    assert!(matches!(params, Err(HybridPirError::InvalidParameters(_))));
+
+    // RaidPIR size larger than the database
+    let params = HybridPirParams::builder(db.len(), 8).raidpir_size(1 << 13).build();
+    assert!(matches!(params, Err(HybridPirError::InvalidParameters(_))));
+
+    let params = HybridPirParams::builder(db.len(), 8).raidpir_size(1 << 8).build().unwrap();
+
+    let mut uneven = db.clone();
+    uneven[5] = vec![0; 4];
+    let server = HybridPirServer::new(&uneven, 0, &params);
+    assert!(matches!(server, Err(HybridPirError::InvalidParameters(_))));
+
+    // Database doesn't match the parameters
+    let server = HybridPirServer::new(&db[..1 << 11].to_vec(), 0, &params);
+    assert!(matches!(server, Err(HybridPirError::InvalidParameters(_))));
+
+    let server = HybridPirServer::new(&db, 2, &params);
+    assert!(matches!(server, Err(HybridPirError::InvalidParameters(_))));
+
+    let client = HybridPirClient::new(&params).unwrap();
+
+    let query = client.query(db.len(), &vec![1234, 4321]);
+    assert!(matches!(query, Err(HybridPirError::IndexOutOfRange { index: 4096, db_len: 4096 })));
+
+    let query = client.query(0, &vec![1234]);
+    assert!(matches!(query, Err(HybridPirError::InvalidParameters(_))));
+
+    // Nobody listening, error should name the server
+    let targets: Vec<SocketAddr> = (0..2)
+        .map(|_| TcpListener::bind(("127.0.0.1", 0)).unwrap().local_addr().unwrap())
+        .collect();
+    let response = client.send_query(&targets, 0);
+    match response {
+        Err(e @ HybridPirError::Io { .. }) => assert!(e.peer().is_some()),
+        _ => panic!("Expected I/O error"),
+    }
+}
+
+#[test]
+fn test_params_mismatch() {
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = vec![vec![0; 8]; size];
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let mut handles = Vec::new();
+    for i in 0..2 {
+        let server = HybridPirServer::new(&db, i, &params).unwrap();
+        handles.push(server.accept_connections(("localhost", 0)).unwrap());
+    }
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let other = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 7)
+        .build()
+        .unwrap();
+
+    let client = HybridPirClient::new(&other).unwrap();
+
+    let response = client.send_query(&targets, 0);
+    match response {
+        Err(HybridPirError::ParamsMismatch { peer, local, remote }) => {
+            assert!(peer.is_some());
+            assert!(local == other.fingerprint());
+            assert!(remote == params.fingerprint());
+        },
+        _ => panic!("Expected parameter mismatch"),
+    }
+}
+
+#[test]
+fn test_planner() {
+    let mut prng = StdRng::from_entropy();
+
+    let size = 1 << 16;
+    let index = size >> 1;
+    let model = CostModel::default();
+
+    // Database too small to split across servers
+    assert!(matches!(plan(16, 8, 2, &model), Err(HybridPirError::InvalidParameters(_))));
+
+    let best = plan(size, 8, 2, &model).unwrap();
+    assert!(candidates(size, 8, 2, &model).iter().all(|c| c.total_time >= best.total_time));
+
+    // Nothing to compare with a broken cost model
+    let broken = CostModel {
+        round_trip: f64::NAN,
+        ..CostModel::default()
+    };
+    assert!(candidates(size, 8, 2, &broken).is_empty());
+    assert!(matches!(plan(size, 8, 2, &broken), Err(HybridPirError::InvalidParameters(_))));
+
+    let mut db: Vec<Vec<u8>> = Vec::with_capacity(size);
+    for _i in 0..size {
+        let mut buffer = vec![0; 8];
+        prng.fill_bytes(&mut buffer);
+        db.push(buffer);
+    }
+    db[index] = b"deadbeef".to_vec();
+
+    let servers: Vec<HybridPirServer> = (0..2)
+        .map(|i| HybridPirServer::new(&db, i, &best.params).unwrap())
+        .collect();
+
+    let client = HybridPirClient::new(&best.params).unwrap();
+
+    let seeds = servers.iter().map(|s| s.seed()).collect();
+    let (raidpir_queries, sealpir_query) = client.query(index, &seeds).unwrap();
+
+    let responses: Vec<PirReply> = servers
+        .iter()
+        .zip(seeds.iter().zip(raidpir_queries.iter()))
+        .map(|(server, (seed, raidpir_query))| server.response(*seed, raidpir_query, client.sealpir_key(), &sealpir_query))
+        .collect();
+
+    assert!(client.combine(index, responses).unwrap() == b"deadbeef");
+}
+
+#[test]
+fn test_keyword() {
+    let mut prng = StdRng::from_entropy();
+
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..1000u32)
+        .map(|i| {
+            let mut value = vec![0; 1 + (i % 16) as usize];
+            prng.fill_bytes(&mut value);
+            (format!("key{}", i).into_bytes(), value)
+        })
+        .collect();
+
+    let (layout, db) = KeywordLayout::build(&entries).unwrap();
+
+    let params = HybridPirParams::builder(db.len(), layout.element_size())
+        .raidpir_size(1 << 7)
+        .build()
+        .unwrap();
+
+    let mut handles = Vec::new();
+    for i in 0..2 {
+        let server = HybridPirServer::new(&db, i, &params).unwrap();
+        handles.push(server.accept_connections(("localhost", 0)).unwrap());
+    }
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let client = HybridPirClient::new(&params).unwrap();
+
+    for (key, value) in [&entries[0], &entries[123], &entries[999]].iter() {
+        let response = client.send_keyword_query(&targets, &layout, key).unwrap();
+        assert!(response.as_ref() == Some(value));
+    }
+
+    let response = client.send_keyword_query(&targets, &layout, b"missing").unwrap();
+    assert!(response.is_none());
+}
+
+#[test]
+fn test_updates() {
+    let mut prng = StdRng::from_entropy();
+
+    let size = 1 << 16;
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let mut db: Vec<Vec<u8>> = Vec::with_capacity(size);
+    for _i in 0..size {
+        let mut buffer = vec![0; 8];
+        prng.fill_bytes(&mut buffer);
+        db.push(buffer);
+    }
+
+    // Updates have to be asked for when setting up the server
+    let updatable = |i| {
+        let mut builder = HybridPirServer::builder(i, &params).unwrap().updatable(true);
+        builder.extend(&db).unwrap();
+        builder.build().unwrap()
+    };
+
+    let servers: Vec<HybridPirServer> = (0..2).map(updatable).collect();
+
+    let mut handles = Vec::new();
+    for server in servers.iter() {
+        let server = server.clone();
+        handles.push(server.accept_connections(("localhost", 0)).unwrap());
+    }
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let client = HybridPirClient::new(&params).unwrap();
+
+    assert!(client.send_query(&targets, 1234).unwrap() == db[1234]);
+
+    for server in servers.iter() {
+        server.update(1234, b"deadbeef".to_vec()).unwrap();
+    }
+
+    assert!(client.send_query(&targets, 1234).unwrap() == b"deadbeef");
+
+    let updates = vec![(0, b"abcdefgh".to_vec()), (size - 1, b"12345678".to_vec())];
+    for server in servers.iter() {
+        server.apply_batch(updates.clone()).unwrap();
+    }
+
+    for (index, value) in updates.iter() {
+        assert!(client.send_query(&targets, *index).unwrap() == *value);
+    }
+
+    // Writing what's already there leaves the RaidPIR server and its queue
+    // alone, while a real change swaps in a new one, preprocessed once
+    let server = updatable(0);
+    server.preprocess();
+    server.preprocess();
+
+    server.apply_batch(vec![(5, db[5].clone())]).unwrap();
+    assert!(server.queue_depth() == 2);
+
+    server.update(5, b"unseen!!".to_vec()).unwrap();
+    assert!(server.queue_depth() == 1);
+
+    let result = servers[0].update(size, b"deadbeef".to_vec());
+    assert!(matches!(result, Err(HybridPirError::IndexOutOfRange { .. })));
+
+    let result = servers[0].update(0, b"dead".to_vec());
+    assert!(matches!(result, Err(HybridPirError::InvalidParameters(_))));
+
+    let server = HybridPirServer::new(&db, 0, &params).unwrap();
+    let result = server.update(0, b"deadbeef".to_vec());
+    assert!(matches!(result, Err(HybridPirError::InvalidParameters(_))));
+}
+
+#[test]
+fn test_from_file() {
+    let mut prng = StdRng::from_entropy();
+
+    let size = 1 << 16;
+    let index = 4321;
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let mut contents = vec![0; size * 8];
+    prng.fill_bytes(&mut contents);
+    contents[index * 8..(index + 1) * 8].copy_from_slice(b"deadbeef");
+
+    let path = std::env::temp_dir().join(format!("hybridpir-test-{}.db", std::process::id()));
+    std::fs::write(&path, &contents).unwrap();
+
+    let servers: Vec<HybridPirServer> = (0..2)
+        .map(|i| HybridPirServer::from_file(&path, i, &params).unwrap())
+        .collect();
+
+    // File doesn't match the parameters
+    let other = HybridPirParams::builder(size * 2, 8).raidpir_size(1 << 8).build().unwrap();
+    let result = HybridPirServer::from_file(&path, 0, &other);
+    assert!(matches!(result, Err(HybridPirError::InvalidParameters(_))));
+
+    std::fs::remove_file(&path).unwrap();
+
+    let client = HybridPirClient::new(&params).unwrap();
+
+    let seeds = servers.iter().map(|s| s.seed()).collect();
+    let (raidpir_queries, sealpir_query) = client.query(index, &seeds).unwrap();
+
+    let responses: Vec<PirReply> = servers
+        .iter()
+        .zip(seeds.iter().zip(raidpir_queries.iter()))
+        .map(|(server, (seed, raidpir_query))| server.response(*seed, raidpir_query, client.sealpir_key(), &sealpir_query))
+        .collect();
+
+    assert!(client.combine(index, responses).unwrap() == b"deadbeef");
+}
+
+#[test]
+fn test_builder() {
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    // Records come in from all sides, not lined up with the chunks
+    let handles: Vec<ServerHandle> = (0..2)
+        .map(|i| {
+            let mut builder = HybridPirServer::builder(i, &params).unwrap();
+            builder.push(&db[0]).unwrap();
+            builder.extend(&db[1..1000]).unwrap();
+            builder.read_from(&db[1000..].concat()[..]).unwrap();
+
+            builder.build().unwrap().accept_connections(("localhost", 0)).unwrap()
+        })
+        .collect();
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+    let client = HybridPirClient::new(&params).unwrap();
+
+    for index in [0, 999, 1000, 1001, size - 1].iter() {
+        assert!(client.send_query(&targets, *index).unwrap() == db[*index]);
+    }
+
+    // Too few records
+    let mut builder = HybridPirServer::builder(0, &params).unwrap();
+    builder.extend(&db[1..]).unwrap();
+    assert!(matches!(builder.build(), Err(HybridPirError::InvalidParameters(_))));
+
+    for handle in handles.iter() {
+        handle.shutdown();
+    }
+}
+
+#[test]
+fn test_variable_length() {
+    let mut prng = StdRng::from_entropy();
+
+    let size = 1 << 12;
+
+    let params = HybridPirParams::builder(size, 904)
+        .raidpir_size(1 << 8)
+        .variable_length(true)
+        .build()
+        .unwrap();
+
+    assert!(params.max_record_size() == 900);
+
+    let db: Vec<Vec<u8>> = (0..size)
+        .map(|_| {
+            let mut buffer = vec![0; 20 + (prng.next_u32() % 881) as usize];
+            prng.fill_bytes(&mut buffer);
+            buffer
+        })
+        .collect();
+
+    let mut too_large = db.clone();
+    too_large[17] = vec![0; 901];
+    let result = HybridPirServer::new(&too_large, 0, &params);
+    assert!(matches!(result, Err(HybridPirError::RecordTooLarge { index: 17, size: 901, max: 900 })));
+
+    let mut handles = Vec::new();
+    for i in 0..2 {
+        let server = HybridPirServer::new(&db, i, &params).unwrap();
+        handles.push(server.accept_connections(("localhost", 0)).unwrap());
+    }
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let client = HybridPirClient::new(&params).unwrap();
+
+    for index in [0, 17, size - 1].iter() {
+        assert!(client.send_query(&targets, *index).unwrap() == db[*index]);
+    }
+
+    let indices = [1, 2, size >> 1];
+    let responses = client.send_query_batch(&targets, &indices).unwrap();
+    for (index, response) in indices.iter().zip(responses.iter()) {
+        assert!(*response == db[*index]);
+    }
+}
+
+#[test]
+fn test_connection_limits() {
+    let mut prng = StdRng::from_entropy();
+
+    let size = 1 << 12;
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let db: Vec<Vec<u8>> = (0..size)
+        .map(|_| {
+            let mut buffer = vec![0; 8];
+            prng.fill_bytes(&mut buffer);
+            buffer
+        })
+        .collect();
+
+    let config = ServerConfig {
+        max_connections: 1,
+        max_pending: 0,
+        ..ServerConfig::default()
+    };
+
+    let mut handles = Vec::new();
+    for i in 0..2 {
+        let server = HybridPirServer::new(&db, i, &params).unwrap();
+        handles.push(server.accept_connections_with(("localhost", 0), &config).unwrap());
+    }
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    // Keeps its connections, and with them the only worker, open
+    let first = HybridPirClient::new(&params).unwrap();
+    assert!(first.send_query(&targets, 1).unwrap() == db[1]);
+
+    let second = HybridPirClient::new(&params).unwrap();
+    let result = second.send_query(&targets, 2);
+    assert!(matches!(result, Err(HybridPirError::Overloaded { peer: Some(_) })));
+
+    first.close();
+
+    // Workers take a moment to notice
+    let mut response = None;
+    for _ in 0..50 {
+        if let Ok(r) = second.send_query(&targets, 2) {
+            response = Some(r);
+            break;
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    assert!(response.unwrap() == db[2]);
+}
+
+#[test]
+fn test_shutdown() {
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let handles: Vec<ServerHandle> = (0..2)
+        .map(|i| {
+            HybridPirServer::new(&db, i, &params)
+                .unwrap()
+                .accept_connections(("localhost", 0))
+                .unwrap()
+        })
+        .collect();
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+    assert!(targets.iter().all(|t| t.port() != 0));
+
+    let client = HybridPirClient::new(&params).unwrap();
+    assert!(client.send_query(&targets, 42).unwrap() == db[42]);
+
+    // Doesn't wait for the idle connections the client kept open
+    let t = std::time::Instant::now();
+    for handle in handles.iter() {
+        handle.shutdown();
+    }
+    assert!(t.elapsed() < std::time::Duration::from_secs(10));
+
+    assert!(client.send_query(&targets, 42).is_err());
+
+    // Dropping a handle stops the server just the same
+    let handle = HybridPirServer::new(&db, 0, &params)
+        .unwrap()
+        .accept_connections(("localhost", 0))
+        .unwrap();
+    let target = handle.local_addr();
+    assert!(TcpStream::connect(target).is_ok());
+
+    drop(handle);
+    assert!(TcpStream::connect(target).is_err());
+}
+
+#[test]
+fn test_preprocessing() {
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    // Seeds precomputed up front, for servers not refilling their queue
+    let start_with = |config: ServerConfig, precomputed| -> Vec<ServerHandle> {
+        (0..2)
+            .map(|i| {
+                let server = HybridPirServer::new(&db, i, &params).unwrap();
+                for _ in 0..precomputed {
+                    server.preprocess();
+                }
+
+                server.accept_connections_with(("localhost", 0), &config).unwrap()
+            })
+            .collect()
+    };
+
+    let start = |queue_depth| -> Vec<ServerHandle> {
+        start_with(ServerConfig {
+            queue_depth,
+            when_queue_empty: EmptyQueue::Reject,
+            ..ServerConfig::default()
+        }, 0)
+    };
+
+    let wait_for_depth = |handles: &[ServerHandle], depth| {
+        for _ in 0..100 {
+            if handles.iter().all(|h| h.queue_depth() == depth) {
+                return true;
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+
+        false
+    };
+
+    let client = HybridPirClient::new(&params).unwrap();
+
+    // Queue is filled in the background, and refilled after queries
+    let handles = start(4);
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    assert!(wait_for_depth(&handles, 4));
+    assert!(client.send_query(&targets, 42).unwrap() == db[42]);
+    assert!(wait_for_depth(&handles, 4));
+
+    // Nothing is precomputed, so every query is turned away
+    let handles = start(0);
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    assert!(handles.iter().all(|h| h.queue_depth() == 0));
+    let result = client.send_query(&targets, 42);
+    assert!(matches!(result, Err(HybridPirError::Overloaded { .. })));
+
+    // Chunks are 16 records each, so this needs four seeds
+    let batch = [0, 16, 32, 48];
+    let expected: Vec<Vec<u8>> = batch.iter().map(|i| db[*i].clone()).collect();
+
+    // Batches needing more seeds than are left are refused without using
+    // any of them up
+    let handles = start_with(ServerConfig {
+        queue_depth: 3,
+        preprocess_threads: 0,
+        when_queue_empty: EmptyQueue::Reject,
+        ..ServerConfig::default()
+    }, 3);
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let result = client.send_query_batch(&targets, &batch);
+    assert!(matches!(result, Err(HybridPirError::Overloaded { .. })));
+    assert!(handles.iter().all(|h| h.queue_depth() == 3));
+
+    assert!(client.send_query_batch(&targets, &batch[..3]).unwrap() == expected[..3].to_vec());
+    assert!(handles.iter().all(|h| h.queue_depth() == 0));
+
+    // Waiting for seeds works for batches larger than the queue, too
+    let handles = start_with(ServerConfig {
+        queue_depth: 2,
+        when_queue_empty: EmptyQueue::Block,
+        ..ServerConfig::default()
+    }, 0);
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    assert!(client.send_query_batch(&targets, &batch).unwrap() == expected);
+    assert!(client.send_query(&targets, 42).unwrap() == db[42]);
+}
+
+#[test]
+fn test_metrics() {
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let config = ServerConfig {
+        metrics_addr: Some("127.0.0.1:0".parse().unwrap()),
+        ..ServerConfig::default()
+    };
+
+    let handles: Vec<ServerHandle> = (0..2)
+        .map(|i| {
+            HybridPirServer::new(&db, i, &params)
+                .unwrap()
+                .accept_connections_with(("localhost", 0), &config)
+                .unwrap()
+        })
+        .collect();
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let client = HybridPirClient::new(&params).unwrap();
+    assert!(client.send_query(&targets, 42).unwrap() == db[42]);
+
+    for handle in handles.iter() {
+        let stats = handle.stats();
+
+        assert!(stats.connections == 1);
+        assert!(stats.active_connections == 1);
+        assert!(stats.errors.is_empty());
+
+        for (_, histogram) in stats.phases.iter() {
+            assert!(histogram.buckets.iter().sum::<u64>() == histogram.count);
+        }
+
+        assert!(stats.messages[&MessageType::Hello].received == 1);
+        assert!(stats.messages[&MessageType::Seed].sent == 1);
+        assert!(stats.messages[&MessageType::Query].received_bytes > 0);
+
+        let mut stream = TcpStream::connect(handle.metrics_addr().unwrap()).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.0 200 OK"));
+        assert!(response.contains("hybridpir_phase_seconds_count{phase=\"response\"} 1"));
+        assert!(response.contains("hybridpir_active_connections 1"));
+    }
+
+    client.close();
+
+    for handle in handles.iter() {
+        handle.shutdown();
+    }
+}
+
+#[test]
+fn test_authentication() {
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let mut tokens = HashMap::new();
+    tokens.insert(b"secret".to_vec(), "alice".to_string());
+
+    let config = ServerConfig {
+        authorizer: Some(Authorizer::tokens(tokens)),
+        client_limit: Some(RateLimit { queries: 3, period: Duration::from_secs(3600) }),
+        ..ServerConfig::default()
+    };
+
+    let handles: Vec<ServerHandle> = (0..2)
+        .map(|i| {
+            HybridPirServer::new(&db, i, &params)
+                .unwrap()
+                .accept_connections_with(("localhost", 0), &config)
+                .unwrap()
+        })
+        .collect();
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let anonymous = HybridPirClient::new(&params).unwrap();
+    let result = anonymous.send_query(&targets, 1);
+    assert!(matches!(result, Err(HybridPirError::Unauthorized { peer: Some(_) })));
+
+    let mut impostor = HybridPirClient::new(&params).unwrap();
+    for target in targets.iter() {
+        impostor.set_token(target, b"guess".to_vec()).unwrap();
+    }
+    let result = impostor.send_query(&targets, 1);
+    assert!(matches!(result, Err(HybridPirError::Unauthorized { peer: Some(_) })));
+
+    let mut client = HybridPirClient::new(&params).unwrap();
+    for target in targets.iter() {
+        client.set_token(target, b"secret".to_vec()).unwrap();
+    }
+
+    assert!(client.send_query(&targets, 1).unwrap() == db[1]);
+    // Touches two chunks, so counts twice
+    assert!(client.send_query_batch(&targets, &[2, 2000]).unwrap() == vec![db[2].clone(), db[2000].clone()]);
+
+    let connections: Vec<u64> = handles.iter().map(|h| h.stats().connections).collect();
+
+    let result = client.send_query(&targets, 4);
+    assert!(matches!(result, Err(HybridPirError::RateLimited { peer: Some(_) })));
+
+    // Turned away on the open connection, so no point in trying a new one
+    assert!(handles.iter().map(|h| h.stats().connections).collect::<Vec<u64>>() == connections);
+
+    // Quotas are per client, not per connection
+    client.close();
+    let result = client.send_query(&targets, 4);
+    assert!(matches!(result, Err(HybridPirError::RateLimited { peer: Some(_) })));
+
+    // Guesses count against the address, and use up its quota
+    let config = ServerConfig {
+        ip_limit: Some(RateLimit { queries: 2, period: Duration::from_secs(3600) }),
+        ..config
+    };
+    let handles: Vec<ServerHandle> = (0..2)
+        .map(|i| {
+            HybridPirServer::new(&db, i, &params)
+                .unwrap()
+                .accept_connections_with(("localhost", 0), &config)
+                .unwrap()
+        })
+        .collect();
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let mut impostor = HybridPirClient::new(&params).unwrap();
+    let mut client = HybridPirClient::new(&params).unwrap();
+    for target in targets.iter() {
+        impostor.set_token(target, b"guess".to_vec()).unwrap();
+        client.set_token(target, b"secret".to_vec()).unwrap();
+    }
+
+    for _ in 0..2 {
+        let result = impostor.send_query(&targets, 1);
+        assert!(matches!(result, Err(HybridPirError::Unauthorized { .. })));
+    }
+
+    let result = client.send_query(&targets, 1);
+    assert!(matches!(result, Err(HybridPirError::RateLimited { .. })));
+}
+
+#[test]
+fn test_framing() {
+    let mut frame = Vec::new();
+    HybridPirMessage::RegisterKey(vec![0; 1024]).write_to(&mut frame).unwrap();
+
+    assert!(HybridPirMessage::read_from(&mut &frame[..]).unwrap() == HybridPirMessage::RegisterKey(vec![0; 1024]));
+
+    let limits = SizeLimits::default().limit(MessageType::RegisterKey, 1023);
+    let result = HybridPirMessage::read_limited(&mut &frame[..], &limits);
+    assert!(matches!(result, Err(HybridPirError::Protocol { .. })));
+
+    let mut wrong_magic = frame.clone();
+    wrong_magic[0] = b'X';
+    let result = HybridPirMessage::read_from(&mut &wrong_magic[..]);
+    assert!(matches!(result, Err(HybridPirError::Protocol { .. })));
+
+    let mut wrong_version = frame.clone();
+    wrong_version[4] = PROTOCOL_VERSION + 1;
+    let result = HybridPirMessage::read_from(&mut &wrong_version[..]);
+    assert!(matches!(result, Err(HybridPirError::Protocol { .. })));
+
+    // Header says Hello, body is something else
+    let mut wrong_type = frame.clone();
+    wrong_type[5] = 0;
+    let result = HybridPirMessage::read_limited(&mut &wrong_type[..], &SizeLimits::default().limit(MessageType::Hello, 2048));
+    assert!(matches!(result, Err(HybridPirError::Protocol { .. })));
+
+    let result = HybridPirMessage::read_from(&mut &frame[..HEADER_SIZE + 100]);
+    assert!(result.unwrap_err().is_disconnect());
+
+    // A server hangs up on a client announcing a huge hello, without
+    // waiting for the body
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let handle = HybridPirServer::new(&db, 0, &params)
+        .unwrap()
+        .accept_connections(("localhost", 0))
+        .unwrap();
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&MAGIC);
+    header.push(PROTOCOL_VERSION);
+    header.push(0); // Hello
+    header.push(0); // Uncompressed
+    header.push(0); // Bincode
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+
+    let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+    stream.write_all(&header).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+    let mut buffer = [0; 1];
+    assert!(matches!(stream.read(&mut buffer), Ok(0) | Err(_)));
+
+    handle.shutdown();
+}
+
+#[test]
+fn test_error_reply() {
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let handle = HybridPirServer::new(&db, 0, &params)
+        .unwrap()
+        .accept_connections(("localhost", 0))
+        .unwrap();
+
+    // Seeds are only ever sent by servers
+    let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+    HybridPirMessage::Seed(1234).write_to(&mut stream).unwrap();
+
+    match HybridPirMessage::read_from(&mut stream).unwrap() {
+        HybridPirMessage::Error { code, message } => {
+            assert!(code == ErrorCode::Protocol);
+            assert!(message.contains("Seed"));
+        },
+        m => panic!("Expected error reply, got {:?}", m),
+    }
+
+    handle.shutdown();
+
+    // Servers failing in their own way are reported by address
+    let listeners: Vec<TcpListener> = (0..2).map(|_| TcpListener::bind(("localhost", 0)).unwrap()).collect();
+    let targets: Vec<SocketAddr> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+
+    // The client may give up before talking to both, so don't wait for them
+    for listener in listeners {
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            HybridPirMessage::read_from(&mut stream).ok();
+
+            let reply = HybridPirMessage::Error {
+                code: ErrorCode::Internal,
+                message: "Out of disk space.".to_string(),
+            };
+            reply.write_to(&mut stream).ok();
+        });
+    }
+
+    let client = HybridPirClient::new(&params).unwrap();
+    match client.send_query(&targets, 0) {
+        Err(e @ HybridPirError::Server { code: ErrorCode::Internal, .. }) => {
+            assert!(targets.contains(&e.peer().unwrap()));
+            assert!(e.to_string().contains("Out of disk space."));
+        },
+        r => panic!("Expected server error, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_malformed_query() {
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    // A single worker, which has to survive the bad query to answer the next
+    let config = ServerConfig {
+        max_connections: 1,
+        ..ServerConfig::default()
+    };
+
+    let handles: Vec<ServerHandle> = (0..2)
+        .map(|i| {
+            HybridPirServer::new(&db, i, &params)
+                .unwrap()
+                .accept_connections_with(("localhost", 0), &config)
+                .unwrap()
+        })
+        .collect();
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+    let client = HybridPirClient::new(&params).unwrap();
+
+    let mut stream = TcpStream::connect(targets[0]).unwrap();
+    HybridPirMessage::Hello(client.session_id(), client.fingerprint(), Vec::new()).write_to(&mut stream).unwrap();
+    assert!(HybridPirMessage::read_from(&mut stream).unwrap() == HybridPirMessage::KeyRequired);
+    HybridPirMessage::RegisterKey(client.sealpir_key().clone()).write_to(&mut stream).unwrap();
+
+    let seed = match HybridPirMessage::read_from(&mut stream).unwrap() {
+        HybridPirMessage::Seed(seed) => seed,
+        m => panic!("Expected seed, got {:?}", m),
+    };
+
+    // Cut short by a byte
+    let (raidpir_queries, sealpir_query) = client.query(0, &vec![seed, seed]).unwrap();
+    let mut raidpir_query = raidpir_queries[0].clone().into_vec();
+    raidpir_query.pop();
+    HybridPirMessage::Query(raidpir_query, sealpir_query).write_to(&mut stream).unwrap();
+
+    match HybridPirMessage::read_from(&mut stream).unwrap() {
+        HybridPirMessage::Error { code, message } => {
+            assert!(code == ErrorCode::Protocol);
+            assert!(message.contains("RaidPIR"));
+        },
+        m => panic!("Expected error reply, got {:?}", m),
+    }
+
+    assert!(client.send_query(&targets, 42).unwrap() == db[42]);
+
+    for handle in handles.iter() {
+        handle.shutdown();
+    }
+}
+
+#[test]
+fn test_streaming() {
+    use std::sync::{Arc, Mutex};
+
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    // Small enough that every response gets streamed
+    let config = ServerConfig {
+        response_part_size: 1 << 10,
+        ..ServerConfig::default()
+    };
+
+    let handles: Vec<ServerHandle> = (0..2)
+        .map(|i| {
+            HybridPirServer::new(&db, i, &params)
+                .unwrap()
+                .accept_connections_with(("localhost", 0), &config)
+                .unwrap()
+        })
+        .collect();
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let progress = Arc::new(Mutex::new(HashMap::new()));
+
+    let mut client = HybridPirClient::new(&params).unwrap();
+    let seen = progress.clone();
+    client.set_progress(move |p| {
+        assert!(p.received <= p.total);
+        seen.lock().unwrap().entry(p.peer).or_insert_with(Vec::new).push((p.received, p.total));
+    });
+
+    // A single reply, reported part by part
+    assert!(client.send_query(&targets, 1).unwrap() == db[1]);
+
+    for target in targets.iter() {
+        let calls = &progress.lock().unwrap()[target];
+        let total = calls[0].1;
+
+        assert!(total > 1 << 10 && calls.len() == (total as usize + (1 << 10) - 1) >> 10);
+        assert!(calls.iter().all(|(_, t)| *t == total));
+        assert!(calls.windows(2).all(|w| w[1].0 - w[0].0 == 1 << 10 || w[1].0 == total));
+        assert!(calls.last().unwrap().0 == total);
+    }
+
+    assert!(client.send_query_batch(&targets, &[2, 3, 2000]).unwrap() == vec![db[2].clone(), db[3].clone(), db[2000].clone()]);
+
+    for handle in handles.iter() {
+        let stats = handle.stats();
+        assert!(stats.messages[&MessageType::ResponseStart].sent == 2);
+        assert!(stats.messages[&MessageType::ResponsePart].sent > 2);
+        assert!(stats.messages.get(&MessageType::Response).map_or(0, |m| m.sent) == 0);
+    }
+
+    client.close();
+
+    for handle in handles {
+        handle.shutdown();
+    }
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_compression() {
+    use hybridpir::types::{Compression, Encoding};
+
+    let message = HybridPirMessage::RegisterKey(vec![0; 1 << 20]);
+
+    let mut plain = Vec::new();
+    message.write_to(&mut plain).unwrap();
+
+    let mut compressed = Vec::new();
+    message.write_encoded(&mut compressed, Encoding::Bincode, Compression::Zstd).unwrap();
+
+    assert!(compressed.len() < plain.len() / 100);
+    assert!(HybridPirMessage::read_from(&mut &compressed[..]).unwrap() == message);
+
+    // Small messages aren't worth it
+    let mut seed = Vec::new();
+    HybridPirMessage::Seed(1234).write_encoded(&mut seed, Encoding::Bincode, Compression::Zstd).unwrap();
+    assert!(seed.len() == HEADER_SIZE + 4 + 16);
+
+    // Limits apply to the decompressed size, too
+    let limits = SizeLimits::default().limit(MessageType::RegisterKey, 1 << 19);
+    let result = HybridPirMessage::read_limited(&mut &compressed[..], &limits);
+    assert!(matches!(result, Err(HybridPirError::Protocol { .. })));
+
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let handles: Vec<ServerHandle> = (0..2)
+        .map(|i| {
+            HybridPirServer::new(&db, i, &params)
+                .unwrap()
+                .accept_connections(("localhost", 0))
+                .unwrap()
+        })
+        .collect();
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let mut client = HybridPirClient::new(&params).unwrap();
+    client.set_compression(vec![Compression::Zstd]).unwrap();
+
+    assert!(client.send_query(&targets, 1).unwrap() == db[1]);
+    assert!(client.send_query_batch(&targets, &[2, 2000]).unwrap() == vec![db[2].clone(), db[2000].clone()]);
+
+    for handle in handles.iter() {
+        let stats = handle.stats();
+        assert!(stats.messages[&MessageType::UseCompression].sent == 2);
+        assert!(stats.messages[&MessageType::RegisterKey].received_bytes > 0);
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_wire_format() {
+    use hybridpir::params::ParamsFingerprint;
+    use hybridpir::types::{ChunkQuery, Compression, Encoding};
+
+    // Pinned frames, see PROTOCOL.md. Anything failing here breaks clients
+    // written in other languages.
+    let fingerprint = ParamsFingerprint {
+        db_len: 4096,
+        element_size: 8,
+        hash: 0x0123456789abcdef,
+    };
+
+    let cases = vec![
+        (HybridPirMessage::Hello(1, fingerprint, vec![Compression::Zstd]),
+            "485049520200000136000000 a16548656c6c6f 8301 a36664625f6c656e191000\
+             6c656c656d656e745f73697a6508 64686173681b0123456789abcdef 81645a737464"),
+        (HybridPirMessage::KeyRequired,
+            "48504952020100010c000000 6b4b65795265717569726564"),
+        (HybridPirMessage::RegisterKey(vec![1, 2, 3]),
+            "485049520202000111000000 a16b52656769737465724b6579 43010203"),
+        (HybridPirMessage::Seed(1234),
+            "485049520203000109000000 a16453656564 1904d2"),
+        (HybridPirMessage::Seed(u128::MAX),
+            "485049520203000118000000 a16453656564 c250ffffffffffffffffffffffffffffffff"),
+        (HybridPirMessage::BatchHello(1, fingerprint, 2, vec![]),
+            "485049520206000137000000 a16a426174636848656c6c6f 8401 a36664625f6c656e191000\
+             6c656c656d656e745f73697a6508 64686173681b0123456789abcdef 02 80"),
+        (HybridPirMessage::ParamsMismatch(fingerprint),
+            "485049520207000137000000 a16e506172616d734d69736d61746368 a36664625f6c656e191000\
+             6c656c656d656e745f73697a6508 64686173681b0123456789abcdef"),
+        (HybridPirMessage::Seeds(vec![1, 1 << 64]),
+            "485049520208000114000000 a16553656564738201 c249010000000000000000"),
+        (HybridPirMessage::BatchQuery(vec![ChunkQuery { raidpir_query: vec![0xff], sealpir_queries: vec![] }]),
+            "48504952020900012f000000 a16a42617463685175657279 81a2\
+             6d726169647069725f7175657279 41ff 6f7365616c7069725f71756572696573 80"),
+        (HybridPirMessage::BatchResponse(vec![vec![]]),
+            "48504952020a000111000000 a16d4261746368526573706f6e7365 8180"),
+        (HybridPirMessage::Close,
+            "48504952020b000106000000 65436c6f7365"),
+        (HybridPirMessage::AuthRequired,
+            "48504952020c00010d000000 6c417574685265717569726564"),
+        (HybridPirMessage::Authenticate(b"token".to_vec()),
+            "48504952020d000114000000 a16c41757468656e74696361746545 746f6b656e"),
+        (HybridPirMessage::Error { code: ErrorCode::RateLimited, message: "Slow down.".to_string() },
+            "48504952020e00012c000000 a1654572726f72 a264636f64656b526174654c696d69746564\
+             676d6573736167656a536c6f7720646f776e2e"),
+        (HybridPirMessage::UseCompression(Compression::Zstd),
+            "48504952020f000115000000 a16e557365436f6d7072657373696f6e 645a737464"),
+        (HybridPirMessage::ResponseStart(vec![1, 2]),
+            "485049520210000112000000 a16d526573706f6e73655374617274 820102"),
+        (HybridPirMessage::ResponsePart(vec![1, 2, 3]),
+            "485049520211000112000000 a16c526573706f6e736550617274 43010203"),
+    ];
+
+    for (message, expected) in cases {
+        let expected: Vec<u8> = expected
+            .split_whitespace()
+            .collect::<String>()
+            .as_bytes()
+            .chunks(2)
+            .map(|c| u8::from_str_radix(std::str::from_utf8(c).unwrap(), 16).unwrap())
+            .collect();
+
+        let mut frame = Vec::new();
+        message.write_encoded(&mut frame, Encoding::Cbor, Compression::None).unwrap();
+
+        assert!(frame == expected, "{} encoded differently", message.name());
+        assert!(HybridPirMessage::read_from(&mut &expected[..]).unwrap() == message);
+    }
+
+    // Trailing garbage is refused, like with bincode
+    let mut frame = Vec::new();
+    HybridPirMessage::Close.write_encoded(&mut frame, Encoding::Cbor, Compression::None).unwrap();
+    frame[8] += 1;
+    frame.push(0);
+    let result = HybridPirMessage::read_from(&mut &frame[..]);
+    assert!(matches!(result, Err(HybridPirError::Deserialization { .. })));
+
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let handles: Vec<ServerHandle> = (0..2)
+        .map(|i| {
+            HybridPirServer::new(&db, i, &params)
+                .unwrap()
+                .accept_connections(("localhost", 0))
+                .unwrap()
+        })
+        .collect();
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    // Error replies come back in the encoding we asked in
+    let mut stream = TcpStream::connect(targets[0]).unwrap();
+    HybridPirMessage::Seed(1234).write_encoded(&mut stream, Encoding::Cbor, Compression::None).unwrap();
+
+    let mut header = [0; HEADER_SIZE];
+    stream.read_exact(&mut header).unwrap();
+    assert!(header[5] == 14 && header[7] == 1);
+
+    let mut client = HybridPirClient::new(&params).unwrap();
+    client.set_encoding(Encoding::Cbor).unwrap();
+
+    assert!(client.send_query(&targets, 1).unwrap() == db[1]);
+    assert!(client.send_query_batch(&targets, &[2, 2000]).unwrap() == vec![db[2].clone(), db[2000].clone()]);
+
+    for handle in handles {
+        handle.shutdown();
+    }
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn test_tls() {
+    use hybridpir::tls;
+
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert_pem = cert.serialize_pem().unwrap();
+    let key_pem = cert.serialize_private_key_pem();
+
+    let config = ServerConfig {
+        tls: Some(tls::server_config(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap()),
+        ..ServerConfig::default()
+    };
+
+    let handles: Vec<ServerHandle> = (0..2)
+        .map(|i| {
+            HybridPirServer::new(&db, i, &params)
+                .unwrap()
+                .accept_connections_with(("localhost", 0), &config)
+                .unwrap()
+        })
+        .collect();
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+    let roots = tls::client_config(cert_pem.as_bytes()).unwrap();
+
+    let mut client = HybridPirClient::new(&params).unwrap();
+    for target in targets.iter() {
+        client.set_tls(target, "localhost", roots.clone()).unwrap();
+    }
+
+    for index in [0, 42, size - 1].iter() {
+        assert!(client.send_query(&targets, *index).unwrap() == db[*index]);
+    }
+
+    // Certificate isn't valid for that name
+    let mut client = HybridPirClient::new(&params).unwrap();
+    for target in targets.iter() {
+        client.set_tls(target, "example.com", roots.clone()).unwrap();
+    }
+
+    assert!(client.send_query(&targets, 42).is_err());
+
+    // Server doesn't speak plain TCP
+    let client = HybridPirClient::new(&params).unwrap();
+    assert!(client.send_query(&targets, 42).is_err());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_async() {
+    use hybridpir::asynchronous::{AsyncHybridPirClient, AsyncHybridPirServer};
+
+    let mut prng = StdRng::from_entropy();
+
+    let size = 1 << 16;
+    let raidpir_servers = 2;
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_servers(raidpir_servers)
+        .raidpir_redundancy(2)
+        .raidpir_size(1 << 8)
+        .sealpir(2048, 12, 2)
+        .build()
+        .unwrap();
+
+    let mut db: Vec<Vec<u8>> = Vec::with_capacity(size);
+    for _i in 0..size {
+        let mut buffer = vec![0; 8];
+        prng.fill_bytes(&mut buffer);
+        db.push(buffer);
+    }
+
+    let mut handles = Vec::with_capacity(raidpir_servers);
+    for i in 0..raidpir_servers {
+        let server = AsyncHybridPirServer::new(HybridPirServer::new(&db, i, &params).unwrap());
+
+        handles.push(server.accept_connections(("localhost", 0)).await.unwrap());
+    }
+
+    let client = AsyncHybridPirClient::new(HybridPirClient::new(&params).unwrap());
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let response = client.send_query(&targets, size >> 1).await.unwrap();
+    assert!(response == db[size >> 1]);
+
+    let indices = [0, 1, size - 1];
+    let responses = client.send_query_batch(&targets, &indices).await.unwrap();
+    for (index, response) in indices.iter().zip(responses.iter()) {
+        assert!(*response == db[*index]);
+    }
+
+    // Doesn't wait for the idle connections the client kept open
+    let t = std::time::Instant::now();
+    for handle in handles.iter() {
+        handle.shutdown().await;
+    }
+    assert!(t.elapsed() < std::time::Duration::from_secs(10));
+
+    assert!(client.send_query(&targets, 42).await.is_err());
+
+    client.close().await;
+}
+
+#[cfg(feature = "async")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_async_authentication() {
+    use hybridpir::asynchronous::{AsyncHybridPirClient, AsyncHybridPirServer};
+
+    let size = 1 << 12;
+    let db: Vec<Vec<u8>> = (0..size).map(|i| (i as u64).to_le_bytes().to_vec()).collect();
+
+    let params = HybridPirParams::builder(size, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let mut tokens = HashMap::new();
+    tokens.insert(b"secret".to_vec(), "alice".to_string());
+
+    let config = ServerConfig {
+        authorizer: Some(Authorizer::tokens(tokens)),
+        client_limit: Some(RateLimit { queries: 2, period: Duration::from_secs(3600) }),
+        ..ServerConfig::default()
+    };
+
+    let mut handles = Vec::with_capacity(2);
+    for i in 0..2 {
+        let server = AsyncHybridPirServer::new(HybridPirServer::new(&db, i, &params).unwrap());
+
+        handles.push(server.accept_connections_with(("localhost", 0), &config).await.unwrap());
+    }
+
+    let targets: Vec<SocketAddr> = handles.iter().map(|h| h.local_addr()).collect();
+
+    let anonymous = AsyncHybridPirClient::new(HybridPirClient::new(&params).unwrap());
+    let result = anonymous.send_query(&targets, 1).await;
+    assert!(matches!(result, Err(HybridPirError::Unauthorized { peer: Some(_) })));
+
+    let mut client = HybridPirClient::new(&params).unwrap();
+    for target in targets.iter() {
+        client.set_token(target, b"secret".to_vec()).unwrap();
+    }
+    let client = AsyncHybridPirClient::new(client);
+
+    assert!(client.send_query(&targets, 1).await.unwrap() == db[1]);
+    assert!(client.send_query(&targets, 2).await.unwrap() == db[2]);
+
+    let result = client.send_query(&targets, 3).await;
+    assert!(matches!(result, Err(HybridPirError::RateLimited { peer: Some(_) })));
+
+    // Waits for the connections to be done, so every error is counted
+    for handle in handles.iter() {
+        handle.shutdown().await;
+
+        let stats = handle.stats();
+        assert!(stats.errors.get("unauthorized") == Some(&1));
+        assert!(stats.errors.get("rate_limited") == Some(&1));
+    }
+
+    client.close().await;
+}
+
+#[cfg(all(feature = "async", feature = "tls"))]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_async_tls() {
+    use hybridpir::asynchronous::AsyncHybridPirClient;
+    use hybridpir::tls;
+
+    let params = HybridPirParams::builder(1 << 12, 8)
+        .raidpir_size(1 << 8)
+        .build()
+        .unwrap();
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let roots = tls::client_config(cert.serialize_pem().unwrap().as_bytes()).unwrap();
+
+    let listeners: Vec<TcpListener> = (0..2).map(|_| TcpListener::bind("localhost:0").unwrap()).collect();
+    let targets: Vec<SocketAddr> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+
+    let mut client = HybridPirClient::new(&params).unwrap();
+    for target in targets.iter() {
+        client.set_tls(target, "localhost", roots.clone()).unwrap();
+        client.set_token(target, b"secret".to_vec()).unwrap();
+    }
+    let client = AsyncHybridPirClient::new(client);
+
+    let result = client.send_query(&targets, 1).await;
+    assert!(matches!(result, Err(HybridPirError::InvalidParameters(_))));
+
+    // Didn't even try a plain connection
+    for listener in listeners.iter() {
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().unwrap_err().kind() == std::io::ErrorKind::WouldBlock);
+    }
+}